@@ -1,14 +1,41 @@
-use std::collections::{BTreeSet, VecDeque};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    ops::Range,
+};
 
 use crate::{
     blobs::{Blob, Blobs},
     board::Board,
+    chunks::CHUNK_SIZE,
     point::Point,
     tiles::Tile,
 };
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum DetectorBackend {
+    // Scans lines of liquid and follows the ones touching them.
+    #[default]
+    Quick,
+    // Two-pass connected-component labelling with union-find.
+    UnionFind,
+}
+
+// Which neighbors of a tile belong to the same blob.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Connectivity {
+    // Up, down, left and right.
+    #[default]
+    #[value(name = "4")]
+    Four,
+    // Diagonals too.
+    #[value(name = "8")]
+    Eight,
+}
+
 pub(crate) struct BlobDetector<'a> {
     board: &'a Board,
+    liquid: Tile,
+    connectivity: Connectivity,
     done: BTreeSet<(usize, usize)>,
 }
 
@@ -23,13 +50,21 @@ struct DetectedLineDef {
 }
 
 impl<'a> BlobDetector<'a> {
-    pub(crate) fn new(board: &'a Board) -> Self {
+    // Each liquid has its own blobs, they never mix with each other.
+    pub(crate) fn new(board: &'a Board, liquid: Tile) -> Self {
         Self {
             board,
+            liquid,
+            connectivity: Default::default(),
             done: Default::default(),
         }
     }
 
+    pub(crate) fn with_connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.connectivity = connectivity;
+        self
+    }
+
     fn _try_insert_blob(
         &self,
         x: usize,
@@ -42,7 +77,7 @@ impl<'a> BlobDetector<'a> {
             return;
         }
         *recursion_counter += 1;
-        if self.board.tiles().at(x, y) == Some(&Tile::Water)
+        if self.board.tiles().at(x, y) == Some(&self.liquid)
             && !current_blob.points().contains(&Point::new(x, y))
         {
             current_blob.points_mut().insert(Point::new(x, y));
@@ -51,6 +86,12 @@ impl<'a> BlobDetector<'a> {
             self._try_insert_blob(x - 1, y, current_blob, recursion_counter, visited);
             self._try_insert_blob(x, y + 1, current_blob, recursion_counter, visited);
             self._try_insert_blob(x, y - 1, current_blob, recursion_counter, visited);
+            if self.connectivity == Connectivity::Eight {
+                self._try_insert_blob(x + 1, y + 1, current_blob, recursion_counter, visited);
+                self._try_insert_blob(x + 1, y - 1, current_blob, recursion_counter, visited);
+                self._try_insert_blob(x - 1, y + 1, current_blob, recursion_counter, visited);
+                self._try_insert_blob(x - 1, y - 1, current_blob, recursion_counter, visited);
+            }
         }
     }
 
@@ -103,7 +144,7 @@ impl<'a> BlobDetector<'a> {
         let mut start = None;
         let mut touching = BTreeSet::new();
 
-        if self.board.tiles().at(sx, sy) == Some(&Tile::Water) {
+        if self.board.tiles().at(sx, sy) == Some(&self.liquid) {
             start = Some(sx);
             self.update_touching(sx, sy, &mut touching);
         }
@@ -112,7 +153,7 @@ impl<'a> BlobDetector<'a> {
             let mut last_x = None;
             // Find to the right
             for x in start + 1..self.board.width() {
-                if self.board.tiles().at(x, sy) != Some(&Tile::Water) {
+                if self.board.tiles().at(x, sy) != Some(&self.liquid) {
                     break;
                 } else {
                     last_x = Some(x);
@@ -122,7 +163,7 @@ impl<'a> BlobDetector<'a> {
 
             // Find to the left
             for x in (0..start).rev() {
-                if self.board.tiles().at(x, sy) != Some(&Tile::Water) {
+                if self.board.tiles().at(x, sy) != Some(&self.liquid) {
                     return Some(DetectedLineDef {
                         start: x + 1,
                         end: last_x.unwrap_or(sx),
@@ -140,13 +181,17 @@ impl<'a> BlobDetector<'a> {
     fn update_touching(&mut self, x: usize, y: usize, touching: &mut BTreeSet<(usize, usize)>) {
         self.done.insert((x, y));
 
-        [y - 1, y + 1]
-            .into_iter()
-            .filter(|y| {
-                self.board.tiles().at(x, *y) == Some(&Tile::Water) && !self.done.contains(&(x, *y))
+        let xs = match self.connectivity {
+            Connectivity::Four => x..=x,
+            Connectivity::Eight => x.saturating_sub(1)..=x + 1,
+        };
+        xs.flat_map(|x| [(x, y - 1), (x, y + 1)])
+            .filter(|(x, y)| {
+                self.board.tiles().at(*x, *y) == Some(&self.liquid)
+                    && !self.done.contains(&(*x, *y))
             })
-            .for_each(|y| {
-                touching.insert((x, y));
+            .for_each(|pt| {
+                touching.insert(pt);
             });
     }
 
@@ -156,11 +201,15 @@ impl<'a> BlobDetector<'a> {
     ) -> Option<(usize, usize)> {
         let (mut start_x, start_y) = start_at.unwrap_or_default();
         for y in start_y..self.board.height() {
-            for x in start_x..self.board.width() {
-                if self.board.tiles().at(x, y) == Some(&Tile::Water) && !self.done.contains(&(x, y))
-                {
-                    self.done.insert((x, y));
-                    return Some((x, y));
+            // Whole words without liquid are skipped at once.
+            for (i, mut word) in self.board.tiles().row_mask(y, self.liquid).enumerate() {
+                while word != 0 {
+                    let x = i * CHUNK_SIZE + word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    if x >= start_x && !self.done.contains(&(x, y)) {
+                        self.done.insert((x, y));
+                        return Some((x, y));
+                    }
                 }
             }
             start_x = 0;
@@ -168,6 +217,13 @@ impl<'a> BlobDetector<'a> {
         None
     }
 
+    pub(crate) fn detect(&mut self, backend: DetectorBackend) -> Blobs {
+        match backend {
+            DetectorBackend::Quick => self.detect_quick(),
+            DetectorBackend::UnionFind => self.detect_union_find(),
+        }
+    }
+
     // TODO: no mut, hold the `done` as function local variable
     pub(crate) fn detect_quick(&mut self) -> Blobs {
         let mut blobs: Blobs = Default::default();
@@ -210,11 +266,97 @@ impl<'a> BlobDetector<'a> {
             blobs.insert(blobs.len(), blob);
         }
     }
+
+    // The first pass labels each run of liquid in a row after the runs above it, and records
+    // labels which turn out to be the same blob. The second pass resolves the labels to blobs,
+    // numbered in the order they are first found, same as in the other backends. Only the runs
+    // are kept, so big boards with little liquid are cheap.
+    pub(crate) fn detect_union_find(&self) -> Blobs {
+        let width = self.board.width();
+        // Every label points to its parent, 0 is not used.
+        let mut parents = vec![0];
+        // Row, tiles and label of all runs, row by row.
+        let mut runs: Vec<(usize, Range<usize>, usize)> = Default::default();
+        // Runs of the previous row.
+        let mut above = 0..0;
+
+        for y in 0..self.board.height() {
+            let row_start = runs.len();
+            let mut first_above = above.start;
+            for run in self.board.tiles().runs(y, self.liquid) {
+                // Tiles above the run, together with its corners with eight-connectivity.
+                let reach = match self.connectivity {
+                    Connectivity::Four => run.clone(),
+                    Connectivity::Eight => run.start.saturating_sub(1)..(run.end + 1).min(width),
+                };
+                // Runs are sorted, the ones left of this run can't reach the next ones either.
+                while first_above < above.end && runs[first_above].1.end <= reach.start {
+                    first_above += 1;
+                }
+                let mut label = 0;
+                for (_, tiles, neighbor) in &runs[first_above..above.end] {
+                    if tiles.start >= reach.end {
+                        break;
+                    }
+                    label = match label {
+                        0 => *neighbor,
+                        label => union(&mut parents, label, *neighbor),
+                    };
+                }
+                if label == 0 {
+                    label = parents.len();
+                    parents.push(label);
+                }
+                runs.push((y, run, label));
+            }
+            above = row_start..runs.len();
+        }
+
+        let mut blobs: Blobs = Default::default();
+        let mut indices = vec![None; parents.len()];
+        for (y, tiles, label) in runs {
+            let root = find(&mut parents, label);
+            let next_index = blobs.len();
+            let index = *indices[root].get_or_insert(next_index);
+            blobs
+                .entry(index)
+                .or_default()
+                .points_mut()
+                .extend(tiles.map(|x| Point::new(x, y)));
+        }
+        blobs
+    }
+}
+
+fn find(parents: &mut [usize], mut label: usize) -> usize {
+    while parents[label] != label {
+        // Path halving keeps the trees flat.
+        parents[label] = parents[parents[label]];
+        label = parents[label];
+    }
+    label
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) -> usize {
+    let (a, b) = (find(parents, a), find(parents, b));
+    let (root, child) = (a.min(b), a.max(b));
+    parents[child] = root;
+    root
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{blob_detector::BlobDetector, board::Board, point::Point};
+    use std::time::Instant;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        blob_detector::{BlobDetector, Connectivity, DetectorBackend},
+        board::{Board, ColorMatching},
+        palette::Palette,
+        point::Point,
+        tiles::Tile,
+    };
 
     #[test]
     fn detects_blob() {
@@ -230,7 +372,7 @@ mod tests {
                              #o#o#oo##oo#\
                              ############";
         let board = Board::new_from_str(12, 11, TILES);
-        let mut detector = BlobDetector::new(&board);
+        let mut detector = BlobDetector::new(&board, Tile::Water);
         let blobs = detector.detect_quick();
 
         dbg!(&blobs.len());
@@ -263,4 +405,77 @@ mod tests {
         // let result_str: String = result.iter().collect();
         // assert_eq!(result_str, TILES);
     }
+
+    #[test]
+    fn joins_diagonal_neighbors_with_eight_connectivity() {
+        const TILES: &str = "######\
+                             #o.o.#\
+                             #.o..#\
+                             #o..o#\
+                             ######";
+        let board = Board::new_from_str(6, 5, TILES);
+        for (connectivity, count) in [(Connectivity::Four, 5), (Connectivity::Eight, 2)] {
+            for backend in [DetectorBackend::Quick, DetectorBackend::UnionFind] {
+                let blobs = BlobDetector::new(&board, Tile::Water)
+                    .with_connectivity(connectivity)
+                    .detect(backend);
+                assert_eq!(blobs.len(), count, "{backend:?} with {connectivity:?}");
+            }
+            let blobs = BlobDetector::new(&board, Tile::Water)
+                .with_connectivity(connectivity)
+                .detect_slow();
+            assert_eq!(blobs.len(), count, "slow with {connectivity:?}");
+        }
+    }
+
+    #[test]
+    fn union_find_matches_slow_detection() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..50 {
+            // Walled like all boards, the slow detector doesn't check the edges.
+            let (width, height) = (rng.gen_range(3..20), rng.gen_range(3..20));
+            let tiles: String = (0..width * height)
+                .map(|i| match (i % width, i / width) {
+                    (0, _) | (_, 0) => '#',
+                    (x, y) if x == width - 1 || y == height - 1 => '#',
+                    _ => ['#', '.', 'o', 'o', '~'][rng.gen_range(0..5)],
+                })
+                .collect();
+            let board = Board::new_from_str(width, height, &tiles);
+
+            for liquid in Tile::LIQUIDS {
+                for connectivity in [Connectivity::Four, Connectivity::Eight] {
+                    let detector =
+                        BlobDetector::new(&board, liquid).with_connectivity(connectivity);
+                    assert_eq!(
+                        detector.detect_union_find(),
+                        detector.detect_slow(),
+                        "{liquid:?} blobs with {connectivity:?} differ on\n{board}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "timing comparison, run with --release --ignored --nocapture"]
+    fn compare_backends_on_big_picture() {
+        let board = Board::from_image(
+            "resources/woter_big.png",
+            &Palette::default(),
+            ColorMatching::Strict,
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let quick = BlobDetector::new(&board, Tile::Water).detect_quick();
+        let duration_quick = start.elapsed();
+
+        let start = Instant::now();
+        let union_find = BlobDetector::new(&board, Tile::Water).detect_union_find();
+        let duration_union_find = start.elapsed();
+
+        println!("quick={duration_quick:?} union_find={duration_union_find:?}");
+        assert_eq!(quick, union_find);
+    }
 }
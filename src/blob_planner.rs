@@ -0,0 +1,163 @@
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{blobs::Blob, board::Board, chunks::ChunkGrid, point::Point, tiles::Tile};
+
+pub(crate) type Move = (Point, Point);
+
+// Tiles changed by the planned moves, on top of the board. It covers the whole board, so it's
+// created once and reused for many blobs, only the touched tiles are reset in between.
+pub(crate) struct Overlay {
+    tiles: ChunkGrid<Option<Tile>>,
+    touched: Vec<Point>,
+}
+
+impl Overlay {
+    pub(crate) fn new(board: &Board) -> Self {
+        Self {
+            tiles: ChunkGrid::new(board.width(), board.height()),
+            touched: Default::default(),
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> Option<Tile> {
+        self.tiles.get(x, y)
+    }
+
+    fn set(&mut self, pt: &Point, tile: Tile) {
+        self.tiles.set(pt.x(), pt.y(), Some(tile));
+        self.touched.push(pt.clone());
+    }
+
+    fn clear(&mut self) {
+        for pt in self.touched.drain(..) {
+            self.tiles.set(pt.x(), pt.y(), None);
+        }
+    }
+}
+
+// Plans the moves of a single blob without touching the board, so many blobs can be planned
+// at once. Moves already planned for the blob are kept in the overlay.
+pub(crate) struct BlobPlanner<'a> {
+    board: &'a Board,
+    liquid: Tile,
+    blob: &'a Blob,
+    overlay: &'a mut Overlay,
+    moves: Vec<Move>,
+}
+
+impl<'a> BlobPlanner<'a> {
+    pub(crate) fn new(
+        board: &'a Board,
+        liquid: Tile,
+        blob: &'a Blob,
+        overlay: &'a mut Overlay,
+    ) -> Self {
+        overlay.clear();
+        Self {
+            board,
+            liquid,
+            blob,
+            overlay,
+            moves: Default::default(),
+        }
+    }
+
+    pub(crate) fn plan<R: Rng>(mut self, rng: &mut R) -> Vec<Move> {
+        let points: Vec<_> = self.blob.iter(rng).cloned().collect();
+        for pt in points {
+            if let Some(dest_pt) = self.fall_destination(&pt, rng) {
+                self.push(pt, dest_pt);
+            }
+        }
+
+        // Now try to move up.
+        self.level(rng);
+        self.moves
+    }
+
+    fn at(&self, x: usize, y: usize) -> Option<Tile> {
+        self.overlay
+            .get(x, y)
+            .or_else(|| self.board.tiles().at(x, y).copied())
+    }
+
+    fn push(&mut self, from: Point, to: Point) {
+        self.overlay.set(&from, Tile::Air);
+        self.overlay.set(&to, self.liquid);
+        self.moves.push((from, to));
+    }
+
+    // Tries to move the droplet down, or sideways if it can't go down.
+    fn fall_destination<R: Rng>(&self, pt: &Point, rng: &mut R) -> Option<Point> {
+        let tile = self.at(pt.x(), pt.y() + 1)?;
+        if tile.is_air() {
+            return Some(Point::new(pt.x(), pt.y() + 1));
+        }
+
+        let tile_left = self.at(pt.x() - 1, pt.y())?;
+        let tile_right = self.at(pt.x() + 1, pt.y())?;
+        match (tile_left.is_air(), tile_right.is_air()) {
+            (true, true) => {
+                if rng.gen::<bool>() {
+                    Some(Point::new(pt.x() - 1, pt.y()))
+                } else {
+                    Some(Point::new(pt.x() + 1, pt.y()))
+                }
+            }
+            (true, false) => Some(Point::new(pt.x() - 1, pt.y())),
+            (false, true) => Some(Point::new(pt.x() + 1, pt.y())),
+            (false, false) => None,
+        }
+    }
+
+    // Droplets are pushed from the highest free surface of the blob to the lowest one by the
+    // pressure of the liquid in between, so communicating vessels level out. As in Torricelli's
+    // law, the flow grows with the square root of the difference between the two levels.
+    fn level<R: Rng>(&mut self, rng: &mut R) {
+        // Points of the blob as they are after the planned moves.
+        let points = self
+            .blob
+            .points()
+            .iter()
+            .chain(self.moves.iter().map(|(_, to)| to))
+            .filter(|pt| self.at(pt.x(), pt.y()) == Some(self.liquid));
+        let mut surface: Vec<_> = points
+            .filter(|pt| {
+                pt.y()
+                    .checked_sub(1)
+                    .and_then(|y| self.at(pt.x(), y))
+                    .is_some_and(|tile| tile.is_air())
+            })
+            .cloned()
+            .collect();
+        // A droplet may have moved more than once.
+        surface.sort();
+        surface.dedup();
+        let (Some(highest), Some(lowest)) = (surface.first(), surface.last()) else {
+            return;
+        };
+        // Moving a droplet one row down only makes the surface jitter.
+        let difference = lowest.y() - highest.y();
+        if difference < 2 {
+            return;
+        }
+
+        // Random order within the rows, highest droplets go to the lowest spots first.
+        surface.shuffle(rng);
+        let mut sources = surface.clone();
+        sources.sort_by_key(Point::y);
+        let mut destinations: Vec<_> = surface
+            .into_iter()
+            .map(|pt| Point::new(pt.x(), pt.y() - 1))
+            .collect();
+        destinations.sort_by_key(|pt| std::cmp::Reverse(pt.y()));
+
+        let transfers = (difference as f64).sqrt() as usize;
+        for (source, destination) in sources.into_iter().zip(destinations).take(transfers) {
+            if destination.y() <= source.y() {
+                break;
+            }
+            self.push(source, destination);
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{
+    blob_detector::Connectivity,
+    blobs::{Blob, Blobs},
+    chunks::ChunkGrid,
+    point::Point,
+};
+
+// Keeps blobs up to date as their points move around, so the engine doesn't need to
+// re-detect everything from scratch each tick. Moves are recorded as they happen and
+// blobs are split or merged in `reconcile()`, only around the cells that actually changed.
+#[derive(Clone, Debug)]
+pub(crate) struct BlobTracker {
+    width: usize,
+    height: usize,
+    connectivity: Connectivity,
+    owners: ChunkGrid<Option<usize>>,
+    // Owners from before the changes since the last `reconcile()`, for the tiles changed
+    // in the epoch stored along.
+    origins: ChunkGrid<(u32, Option<u32>)>,
+    epoch: u32,
+    // Generation in which the tile was visited, and the flood which got there.
+    visited: ChunkGrid<(u32, u32)>,
+    generation: u32,
+    vacated: Vec<(Point, usize)>,
+    occupied: Vec<Point>,
+}
+
+impl BlobTracker {
+    pub(crate) fn new(
+        width: usize,
+        height: usize,
+        connectivity: Connectivity,
+        blobs: &Blobs,
+    ) -> Self {
+        let mut owners = ChunkGrid::new(width, height);
+        for (index, blob) in blobs {
+            for pt in blob.points() {
+                owners.set(pt.x(), pt.y(), Some(*index));
+            }
+        }
+
+        Self {
+            width,
+            height,
+            connectivity,
+            owners,
+            origins: ChunkGrid::new(width, height),
+            epoch: 1,
+            visited: ChunkGrid::new(width, height),
+            generation: 0,
+            vacated: Default::default(),
+            occupied: Default::default(),
+        }
+    }
+
+    pub(crate) fn owner(&self, x: usize, y: usize) -> Option<usize> {
+        self.owners.get(x, y)
+    }
+
+    fn set_owner(&mut self, pt: &Point, owner: Option<usize>) {
+        if self.origins.get(pt.x(), pt.y()).0 != self.epoch {
+            let origin = self.owner(pt.x(), pt.y()).map(|index| index as u32);
+            self.origins.set(pt.x(), pt.y(), (self.epoch, origin));
+        }
+        self.owners.set(pt.x(), pt.y(), owner);
+    }
+
+    // Belonged to the blob before the changes and still does.
+    fn stayed(&self, pt: &Point, index: usize) -> bool {
+        let (epoch, origin) = self.origins.get(pt.x(), pt.y());
+        self.owner(pt.x(), pt.y()) == Some(index)
+            && (epoch != self.epoch || origin == Some(index as u32))
+    }
+
+    pub(crate) fn move_point(&mut self, blobs: &mut Blobs, index: usize, from: &Point, to: Point) {
+        if let Some(blob) = blobs.get_mut(&index) {
+            blob.points_mut().remove(from);
+            blob.points_mut().insert(to.clone());
+        }
+        self.set_owner(from, None);
+        self.set_owner(&to, Some(index));
+        self.vacated.push((from.clone(), index));
+        self.occupied.push(to);
+    }
+
+    pub(crate) fn remove_point(&mut self, blobs: &mut Blobs, pt: Point) {
+        let Some(index) = self.owner(pt.x(), pt.y()) else {
+            return;
+        };
+        if let Some(blob) = blobs.get_mut(&index) {
+            blob.points_mut().remove(&pt);
+        }
+        self.set_owner(&pt, None);
+        self.vacated.push((pt, index));
+    }
+
+    pub(crate) fn insert_point(&mut self, blobs: &mut Blobs, pt: Point) {
+        if self.owner(pt.x(), pt.y()).is_some() {
+            return;
+        }
+        // Starts as a single droplet, `reconcile()` will merge it with whatever it touches.
+        let index = Self::next_index(blobs);
+        blobs.insert(index, Blob::new([pt.clone()].into()));
+        self.set_owner(&pt, Some(index));
+        self.occupied.push(pt);
+    }
+
+    pub(crate) fn reconcile(&mut self, blobs: &mut Blobs) {
+        let vacated = std::mem::take(&mut self.vacated);
+        let occupied = std::mem::take(&mut self.occupied);
+
+        // Split: only blobs that lost a point may have been torn apart. Any part of them holds
+        // a neighbor of a tile they lost, or is made only of points which arrived, so those
+        // of the arrived points which don't touch the old ones are seeds too.
+        let mut seeds_by_blob: BTreeMap<usize, Vec<Point>> = Default::default();
+        for (pt, index) in vacated {
+            let seeds = seeds_by_blob.entry(index).or_default();
+            // Filled again by the blob, nothing was lost there.
+            if self.owner(pt.x(), pt.y()) == Some(index) {
+                continue;
+            }
+            seeds.extend(
+                self.neighbors(&pt)
+                    .filter(|seed| self.owner(seed.x(), seed.y()) == Some(index)),
+            );
+        }
+        for pt in &occupied {
+            let Some(index) = self.owner(pt.x(), pt.y()) else {
+                continue;
+            };
+            if self.stayed(pt, index)
+                || self
+                    .neighbors(pt)
+                    .any(|neighbor| self.stayed(&neighbor, index))
+            {
+                continue;
+            }
+            if let Some(seeds) = seeds_by_blob.get_mut(&index) {
+                seeds.push(pt.clone());
+            }
+        }
+        for (index, seeds) in seeds_by_blob {
+            self.split(blobs, index, seeds);
+        }
+
+        // Merge: blobs can only join where a point has just arrived.
+        for pt in occupied {
+            let Some(mut index) = self.owner(pt.x(), pt.y()) else {
+                continue;
+            };
+            for neighbor in self.neighbors(&pt) {
+                if let Some(other) = self.owner(neighbor.x(), neighbor.y()) {
+                    if other != index {
+                        index = self.merge(blobs, index.min(other), index.max(other));
+                    }
+                }
+            }
+        }
+        self.next_epoch();
+    }
+
+    // Every part of a torn blob holds a seed. A flood starts from each of them and they all
+    // advance together, joining where they meet. It's over once a single flood is left, or
+    // when no more than one can go any further. Only the parts which broke off are walked
+    // in full, not the whole blob.
+    fn split(&mut self, blobs: &mut Blobs, index: usize, seeds: Vec<Point>) {
+        let Some(blob) = blobs.get_mut(&index) else {
+            return;
+        };
+        if blob.points().is_empty() {
+            blobs.remove(&index);
+            return;
+        }
+
+        self.next_generation();
+        let mut floods = Floods::default();
+        // Points with the flood which reached them, in the order they were reached.
+        let mut reached = Vec::new();
+        let mut pending = VecDeque::new();
+        for seed in seeds {
+            if self.visited_by(&seed).is_none() {
+                let flood = floods.start();
+                self.mark_visited(&seed, flood);
+                reached.push((seed.clone(), flood));
+                pending.push_back((seed, flood));
+            }
+        }
+        while floods.separate > 1 && floods.walking > 1 {
+            let Some((pt, flood)) = pending.pop_front() else {
+                break;
+            };
+            let mut flood = floods.root(flood);
+            for neighbor in self.neighbors(&pt) {
+                if self.owner(neighbor.x(), neighbor.y()) != Some(index) {
+                    continue;
+                }
+                match self.visited_by(&neighbor) {
+                    Some(other) => {
+                        let other = floods.root(other);
+                        if other != flood {
+                            flood = floods.join(flood, other);
+                        }
+                    }
+                    None => {
+                        self.mark_visited(&neighbor, flood);
+                        floods.reach(flood);
+                        reached.push((neighbor.clone(), flood));
+                        pending.push_back((neighbor, flood));
+                    }
+                }
+            }
+            floods.visit(flood);
+        }
+        if floods.separate < 2 {
+            return;
+        }
+
+        // Parts are complete where the flood has finished, the one which hasn't is whatever
+        // is left of the blob.
+        let mut parts: BTreeMap<usize, BTreeSet<Point>> = Default::default();
+        for (pt, flood) in reached {
+            let flood = floods.root(flood);
+            if floods.pending[flood] == 0 {
+                parts.entry(flood).or_default().insert(pt);
+            }
+        }
+        let mut rest = std::mem::take(blob.points_mut());
+        for pt in parts.values().flatten() {
+            rest.remove(pt);
+        }
+        let mut components: Vec<_> = parts.into_values().collect();
+        // Collected again, a set packed anew is quicker to go through than one which lost points.
+        if !rest.is_empty() {
+            components.insert(0, rest.into_iter().collect());
+        }
+
+        // The largest part keeps the original index, the rest become new blobs.
+        let largest = components
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, component)| (component.len(), std::cmp::Reverse(*i)))
+            .map(|(i, _)| i)
+            .unwrap_or_default();
+        let kept = components.swap_remove(largest);
+        blobs.insert(index, Blob::new(kept));
+
+        for component in components {
+            let new_index = Self::next_index(blobs);
+            for pt in &component {
+                self.set_owner(pt, Some(new_index));
+            }
+            blobs.insert(new_index, Blob::new(component));
+        }
+    }
+
+    fn merge(&mut self, blobs: &mut Blobs, keep: usize, gone: usize) -> usize {
+        if let Some(gone_blob) = blobs.remove(&gone) {
+            for pt in gone_blob.points() {
+                self.set_owner(pt, Some(keep));
+            }
+            blobs
+                .entry(keep)
+                .or_default()
+                .points_mut()
+                .extend(gone_blob.points().iter().cloned());
+        }
+        keep
+    }
+
+    fn next_epoch(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        if self.epoch == 0 {
+            self.origins.clear();
+            self.epoch = 1;
+        }
+    }
+
+    fn next_generation(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.visited.clear();
+            self.generation = 1;
+        }
+    }
+
+    fn visited_by(&self, pt: &Point) -> Option<usize> {
+        let (generation, flood) = self.visited.get(pt.x(), pt.y());
+        (generation == self.generation).then_some(flood as usize)
+    }
+
+    fn mark_visited(&mut self, pt: &Point, flood: usize) {
+        self.visited
+            .set(pt.x(), pt.y(), (self.generation, flood as u32));
+    }
+
+    fn neighbors(&self, pt: &Point) -> impl Iterator<Item = Point> {
+        let (x, y, width, height) = (pt.x(), pt.y(), self.width, self.height);
+        [
+            (x > 0).then(|| Point::new(x - 1, y)),
+            (x + 1 < width).then(|| Point::new(x + 1, y)),
+            (y > 0).then(|| Point::new(x, y - 1)),
+            (y + 1 < height).then(|| Point::new(x, y + 1)),
+        ]
+        .into_iter()
+        .chain(self.diagonals(pt))
+        .flatten()
+    }
+
+    fn diagonals(&self, pt: &Point) -> [Option<Point>; 4] {
+        if self.connectivity == Connectivity::Four {
+            return [None, None, None, None];
+        }
+        let (x, y, width, height) = (pt.x(), pt.y(), self.width, self.height);
+        [
+            (x > 0 && y > 0).then(|| Point::new(x - 1, y - 1)),
+            (x + 1 < width && y > 0).then(|| Point::new(x + 1, y - 1)),
+            (x > 0 && y + 1 < height).then(|| Point::new(x - 1, y + 1)),
+            (x + 1 < width && y + 1 < height).then(|| Point::new(x + 1, y + 1)),
+        ]
+    }
+
+    fn next_index(blobs: &Blobs) -> usize {
+        blobs.keys().last().map_or(0, |last_key| last_key + 1)
+    }
+}
+
+// Floods of a split, one for every seed. Those which met are joined under the larger one.
+#[derive(Default)]
+struct Floods {
+    joined: Vec<Option<usize>>,
+    // Points reached by the flood and those of them still to be visited, of the joined
+    // floods included.
+    reached: Vec<usize>,
+    pending: Vec<usize>,
+    // Floods which haven't been joined, and those of them which can still go further.
+    separate: usize,
+    walking: usize,
+}
+
+impl Floods {
+    fn start(&mut self) -> usize {
+        self.joined.push(None);
+        self.reached.push(1);
+        self.pending.push(1);
+        self.separate += 1;
+        self.walking += 1;
+        self.joined.len() - 1
+    }
+
+    // Flood which the given one ended up in.
+    fn root(&self, mut flood: usize) -> usize {
+        while let Some(joined) = self.joined[flood] {
+            flood = joined;
+        }
+        flood
+    }
+
+    fn reach(&mut self, flood: usize) {
+        self.reached[flood] += 1;
+        self.pending[flood] += 1;
+    }
+
+    fn visit(&mut self, flood: usize) {
+        self.pending[flood] -= 1;
+        if self.pending[flood] == 0 {
+            self.walking -= 1;
+        }
+    }
+
+    fn join(&mut self, flood: usize, other: usize) -> usize {
+        let (kept, gone) = if self.reached[flood] >= self.reached[other] {
+            (flood, other)
+        } else {
+            (other, flood)
+        };
+        if self.pending[kept] > 0 && self.pending[gone] > 0 {
+            self.walking -= 1;
+        }
+        self.joined[gone] = Some(kept);
+        self.reached[kept] += self.reached[gone];
+        self.pending[kept] += self.pending[gone];
+        self.separate -= 1;
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        blob_detector::Connectivity,
+        blobs::{Blob, Blobs},
+        point::Point,
+    };
+
+    use super::BlobTracker;
+
+    fn blob(points: &[(usize, usize)]) -> Blob {
+        Blob::new(points.iter().map(|(x, y)| Point::new(*x, *y)).collect())
+    }
+
+    #[test]
+    fn splits_blob_in_two() {
+        let mut blobs: Blobs =
+            [(0, blob(&[(1, 1), (2, 1), (3, 1), (4, 1), (5, 1), (6, 1)]))].into();
+        let mut tracker = BlobTracker::new(8, 3, Connectivity::Four, &blobs);
+
+        tracker.remove_point(&mut blobs, Point::new(3, 1));
+        tracker.reconcile(&mut blobs);
+
+        // The larger part keeps the index.
+        let expected: Blobs = [
+            (0, blob(&[(4, 1), (5, 1), (6, 1)])),
+            (1, blob(&[(1, 1), (2, 1)])),
+        ]
+        .into();
+        assert_eq!(blobs, expected);
+        assert_eq!(tracker.owner(1, 1), Some(1));
+        assert_eq!(tracker.owner(3, 1), None);
+    }
+
+    #[test]
+    fn keeps_falling_blob_whole() {
+        let column = [(2, 1), (2, 2), (2, 3)];
+        let mut blobs: Blobs = [(4, blob(&column))].into();
+        let mut tracker = BlobTracker::new(5, 6, Connectivity::Four, &blobs);
+
+        // Bottom first, every point into the tile just left by the one below.
+        for (x, y) in column.into_iter().rev() {
+            tracker.move_point(&mut blobs, 4, &Point::new(x, y), Point::new(x, y + 1));
+        }
+        tracker.reconcile(&mut blobs);
+
+        let expected: Blobs = [(4, blob(&[(2, 2), (2, 3), (2, 4)]))].into();
+        assert_eq!(blobs, expected);
+    }
+
+    #[test]
+    fn splits_off_point_moved_away() {
+        let mut blobs: Blobs = [(0, blob(&[(1, 1), (2, 1), (3, 1), (4, 1)]))].into();
+        let mut tracker = BlobTracker::new(8, 5, Connectivity::Four, &blobs);
+
+        tracker.move_point(&mut blobs, 0, &Point::new(4, 1), Point::new(6, 3));
+        tracker.reconcile(&mut blobs);
+
+        let expected: Blobs = [(0, blob(&[(1, 1), (2, 1), (3, 1)])), (1, blob(&[(6, 3)]))].into();
+        assert_eq!(blobs, expected);
+        assert_eq!(tracker.owner(6, 3), Some(1));
+    }
+
+    #[test]
+    fn merges_into_lower_index() {
+        let mut blobs: Blobs = [(2, blob(&[(1, 1)])), (5, blob(&[(3, 1)]))].into();
+        let mut tracker = BlobTracker::new(6, 3, Connectivity::Four, &blobs);
+
+        tracker.move_point(&mut blobs, 5, &Point::new(3, 1), Point::new(2, 1));
+        tracker.reconcile(&mut blobs);
+
+        let expected: Blobs = [(2, blob(&[(1, 1), (2, 1)]))].into();
+        assert_eq!(blobs, expected);
+        assert_eq!(tracker.owner(2, 1), Some(2));
+    }
+
+    #[test]
+    fn merges_diagonal_touch_only_with_eight_neighbors() {
+        for (connectivity, count) in [(Connectivity::Four, 2), (Connectivity::Eight, 1)] {
+            let mut blobs: Blobs = [(0, blob(&[(1, 1)])), (1, blob(&[(3, 3)]))].into();
+            let mut tracker = BlobTracker::new(5, 5, connectivity, &blobs);
+
+            tracker.move_point(&mut blobs, 1, &Point::new(3, 3), Point::new(2, 2));
+            tracker.reconcile(&mut blobs);
+
+            assert_eq!(blobs.len(), count, "{connectivity:?}");
+            assert_eq!(tracker.owner(2, 2), Some(count - 1), "{connectivity:?}");
+        }
+    }
+}
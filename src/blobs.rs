@@ -1,12 +1,20 @@
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use itertools::Itertools;
-use rand::{rngs::ThreadRng, Rng};
+use rand::Rng;
 
 use crate::point::Point;
 
 pub(crate) type Blobs = BTreeMap<usize, Blob>;
 
+// Both split the tiles into the same blobs, whatever their indices.
+pub(crate) fn same_partition(blobs: &Blobs, other: &Blobs) -> bool {
+    fn partition(blobs: &Blobs) -> BTreeSet<&BTreeSet<Point>> {
+        blobs.values().map(Blob::points).collect()
+    }
+    partition(blobs) == partition(other)
+}
+
 #[derive(Clone, Default, Debug, PartialEq)]
 pub(crate) struct Blob {
     points: BTreeSet<Point>,
@@ -24,14 +32,9 @@ impl Blob {
     pub(crate) fn points_mut(&mut self) -> &mut BTreeSet<Point> {
         &mut self.points
     }
-}
-
-impl<'a> IntoIterator for &'a Blob {
-    type Item = &'a Point;
-    type IntoIter = PointIterator<'a>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        PointIterator::new(&self.points)
+    pub(crate) fn iter<'a, R: Rng>(&'a self, rng: &'a mut R) -> PointIterator<'a, R> {
+        PointIterator::new(&self.points, rng)
     }
 }
 
@@ -42,14 +45,14 @@ impl<'a> IntoIterator for &'a Blob {
 // which will make this iterator superfluous because the iteration will get trivial.
 // Anyway: measure first :)
 #[derive(Debug)]
-pub(crate) struct PointIterator<'a> {
+pub(crate) struct PointIterator<'a, R: Rng> {
     points: HashMap<usize, BTreeSet<&'a Point>>,
     keys: BTreeSet<usize>,
-    rng: ThreadRng,
+    rng: &'a mut R,
 }
 
-impl<'a> PointIterator<'a> {
-    pub(crate) fn new(points: &'a BTreeSet<Point>) -> Self {
+impl<'a, R: Rng> PointIterator<'a, R> {
+    pub(crate) fn new(points: &'a BTreeSet<Point>, rng: &'a mut R) -> Self {
         let grouped_points = points
             .iter()
             .into_grouping_map_by(|pt| pt.y())
@@ -58,12 +61,12 @@ impl<'a> PointIterator<'a> {
         Self {
             keys: grouped_points.keys().cloned().collect(),
             points: grouped_points,
-            rng: rand::thread_rng(),
+            rng,
         }
     }
 }
 
-impl<'a> Iterator for PointIterator<'a> {
+impl<'a, R: Rng> Iterator for PointIterator<'a, R> {
     type Item = &'a Point;
     fn next(&mut self) -> Option<Self::Item> {
         if self.keys.is_empty() {
@@ -98,6 +101,8 @@ impl<'a> Iterator for PointIterator<'a> {
 
 #[cfg(test)]
 mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
     use crate::point::Point;
 
     use super::Blob;
@@ -139,7 +144,10 @@ mod tests {
                 Point::new(3, 3),
             ]);
 
-            let actual = blob.into_iter().cloned().collect::<Vec<_>>();
+            let actual = blob
+                .iter(&mut rand::thread_rng())
+                .cloned()
+                .collect::<Vec<_>>();
             if actual != not_expected {
                 return;
             }
@@ -147,4 +155,20 @@ mod tests {
 
         panic!("more than {ALLOWED_FALSE_POSITIVES} false positives :(");
     }
+
+    #[test]
+    fn should_iter_points_in_the_same_order_for_the_same_seed() {
+        let blob = Blob::from_iter((0..5).flat_map(|y| (0..5).map(move |x| Point::new(x, y))));
+
+        let first = blob
+            .iter(&mut StdRng::seed_from_u64(2137))
+            .cloned()
+            .collect::<Vec<_>>();
+        let second = blob
+            .iter(&mut StdRng::seed_from_u64(2137))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        assert_eq!(first, second);
+    }
 }
@@ -1,8 +1,34 @@
-use std::path::Path;
+use std::{fmt, io, path::Path};
 
-use crate::tiles::{Tile, Tiles};
+use image::{ImageError, ImageFormat, Rgb, RgbImage};
+use thiserror::Error;
 
-#[derive(Clone, Debug)]
+use crate::{
+    palette::Palette,
+    tiles::{Tile, Tiles},
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("i/o error: {0}")]
+    Io(io::Error),
+    #[error("unable to decode image: {0}")]
+    Decode(ImageError),
+    #[error("unable to encode image: {0}")]
+    Encode(ImageError),
+    #[error("unsupported color {color:?} at ({x}, {y})")]
+    UnsupportedColor { x: usize, y: usize, color: [u8; 3] },
+}
+
+// What to do with pixels which colors don't map to any tile.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ColorMatching {
+    #[default]
+    Strict,
+    Lenient,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct Board {
     width: usize,
     height: usize,
@@ -53,29 +79,67 @@ impl Board {
         self.height
     }
 
-    // This will eventually be used when we add an option to load the board from bitmap.
-    pub(crate) fn from_image(path: impl AsRef<Path>) -> Self {
-        let image = image::open(path).unwrap().to_rgb8();
-        let pixels = image.enumerate_pixels();
+    pub(crate) fn from_image(
+        path: impl AsRef<Path>,
+        palette: &Palette,
+        mode: ColorMatching,
+    ) -> Result<Self, Error> {
+        let image = image::open(path)
+            .map_err(|err| match err {
+                ImageError::IoError(err) => Error::Io(err),
+                err => Error::Decode(err),
+            })?
+            .to_rgb8();
+        Self::from_rgb_image(&image, palette, mode)
+    }
 
+    fn from_rgb_image(
+        image: &RgbImage,
+        palette: &Palette,
+        mode: ColorMatching,
+    ) -> Result<Self, Error> {
         let mut board = Self {
             width: image.width() as usize,
             height: image.height() as usize,
             tiles: Tiles::empty(image.width() as usize, image.height() as usize),
         };
 
-        for (x, y, rgb) in pixels {
-            match rgb.0 {
-                // Black
-                [0, 0, 0] => board.tiles.set_at(x as usize, y as usize, Tile::Rock),
-                // White
-                [255, 255, 255] => (),
-                // Blue
-                [0, 0, 255] => board.tiles.set_at(x as usize, y as usize, Tile::Water),
-                _ => (), //panic!("unsupported color"),
-            }
+        for (x, y, rgb) in image.enumerate_pixels() {
+            let (x, y) = (x as usize, y as usize);
+            let tile = match (palette.tile(rgb.0), mode) {
+                (Some(tile), _) => tile,
+                (None, ColorMatching::Lenient) => palette.nearest_tile(rgb.0),
+                (None, ColorMatching::Strict) => {
+                    return Err(Error::UnsupportedColor { x, y, color: rgb.0 })
+                }
+            };
+            board.tiles.set_at(x, y, tile);
         }
-        board
+        Ok(board)
+    }
+
+    pub(crate) fn to_image(&self, palette: &Palette) -> RgbImage {
+        RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let tile = self
+                .tiles
+                .at(x as usize, y as usize)
+                .copied()
+                .unwrap_or(Tile::Air);
+            Rgb(palette.color(tile))
+        })
+    }
+
+    pub(crate) fn save_image(
+        &self,
+        path: impl AsRef<Path>,
+        palette: &Palette,
+    ) -> Result<(), Error> {
+        self.to_image(palette)
+            .save_with_format(path, ImageFormat::Png)
+            .map_err(|err| match err {
+                ImageError::IoError(err) => Error::Io(err),
+                err => Error::Encode(err),
+            })
     }
 
     #[cfg(test)]
@@ -114,3 +178,98 @@ impl Board {
         }
     }
 }
+
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in 0..self.height {
+            for tile in self.tiles.row(y) {
+                write!(f, "{}", tile.symbol())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use crate::{palette::Palette, tiles::Tile};
+
+    use super::{Board, ColorMatching, Error};
+
+    fn image() -> RgbImage {
+        let mut image = RgbImage::from_pixel(3, 2, Rgb([255, 255, 255]));
+        image.put_pixel(0, 0, Rgb([0, 0, 0]));
+        image.put_pixel(1, 1, Rgb([0, 0, 255]));
+        image
+    }
+
+    #[test]
+    fn imports_image() {
+        let board =
+            Board::from_rgb_image(&image(), &Palette::default(), ColorMatching::Strict).unwrap();
+
+        assert_eq!(board.to_string(), "#..\n.o.\n");
+    }
+
+    #[test]
+    fn rejects_unknown_color_in_strict_mode() {
+        let mut image = image();
+        image.put_pixel(2, 1, Rgb([10, 20, 200]));
+
+        let result = Board::from_rgb_image(&image, &Palette::default(), ColorMatching::Strict);
+
+        assert!(matches!(
+            result,
+            Err(Error::UnsupportedColor {
+                x: 2,
+                y: 1,
+                color: [10, 20, 200]
+            })
+        ));
+    }
+
+    #[test]
+    fn maps_unknown_color_to_nearest_in_lenient_mode() {
+        let mut image = image();
+        image.put_pixel(2, 1, Rgb([10, 20, 200]));
+        image.put_pixel(2, 0, Rgb([40, 30, 30]));
+
+        let board =
+            Board::from_rgb_image(&image, &Palette::default(), ColorMatching::Lenient).unwrap();
+
+        assert_eq!(board.tiles().at(2, 1), Some(&Tile::Water));
+        assert_eq!(board.tiles().at(2, 0), Some(&Tile::Rock));
+    }
+
+    #[test]
+    fn exports_image_unchanged() {
+        let palette = Palette::default().with_entries("water:1030c0~8").unwrap();
+        let mut image = image();
+        image.put_pixel(1, 1, Rgb([16, 48, 192]));
+
+        let board = Board::from_rgb_image(&image, &palette, ColorMatching::Strict).unwrap();
+
+        assert_eq!(board.to_image(&palette), image);
+    }
+
+    #[test]
+    fn reports_missing_file() {
+        let result = Board::from_image(
+            "does/not/exist.png",
+            &Palette::default(),
+            ColorMatching::Strict,
+        );
+
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn displays_board_row_by_row() {
+        let board = Board::new_from_str(4, 3, "#..#o.o#####");
+
+        assert_eq!(board.to_string(), "#..#\no.o#\n####\n");
+    }
+}
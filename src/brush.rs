@@ -0,0 +1,100 @@
+const MAX_RADIUS: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum BrushShape {
+    #[default]
+    Square,
+    Circle,
+    // A single tile, whatever the radius.
+    Pixel,
+}
+
+impl BrushShape {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Self::Square => Self::Circle,
+            Self::Circle => Self::Pixel,
+            Self::Pixel => Self::Square,
+        }
+    }
+}
+
+// Tiles changed around the cursor while painting. Radius is in tiles, so the brush covers
+// the same part of the board whatever the zoom.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Brush {
+    pub(crate) shape: BrushShape,
+    pub(crate) radius: usize,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            shape: Default::default(),
+            radius: 2,
+        }
+    }
+}
+
+impl Brush {
+    pub(crate) fn resize(&mut self, steps: isize) {
+        self.radius = self.radius.saturating_add_signed(steps).min(MAX_RADIUS);
+    }
+
+    // Tiles under the brush centered at the given board tile. Those left of or above
+    // the board are skipped, the others are up to the caller.
+    pub(crate) fn tiles(&self, x: isize, y: isize) -> Vec<(usize, usize)> {
+        let radius = match self.shape {
+            BrushShape::Pixel => 0,
+            _ => self.radius as isize,
+        };
+        let mut tiles = Vec::new();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                // Slightly over the radius, so there are no lone tiles sticking out.
+                if self.shape == BrushShape::Circle && dx * dx + dy * dy > radius * radius + radius
+                {
+                    continue;
+                }
+                if let (Ok(x), Ok(y)) = (usize::try_from(x + dx), usize::try_from(y + dy)) {
+                    tiles.push((x, y));
+                }
+            }
+        }
+        tiles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Brush, BrushShape};
+
+    #[test]
+    fn covers_tiles_of_its_shape() {
+        let mut brush = Brush {
+            shape: BrushShape::Circle,
+            radius: 2,
+        };
+        let circle = brush.tiles(5, 5);
+        assert_eq!(circle.len(), 21);
+        assert!(circle.contains(&(7, 5)) && circle.contains(&(6, 6)));
+        assert!(!circle.contains(&(7, 7)));
+
+        brush.shape = BrushShape::Square;
+        assert_eq!(brush.tiles(5, 5).len(), 25);
+        // Cut off by the edges of the board.
+        assert_eq!(brush.tiles(0, 1).len(), 12);
+
+        brush.shape = BrushShape::Pixel;
+        assert_eq!(brush.tiles(5, 5), [(5, 5)]);
+    }
+
+    #[test]
+    fn keeps_radius_in_limits() {
+        let mut brush = Brush::default();
+        brush.resize(-5);
+        assert_eq!(brush.radius, 0);
+        brush.resize(1000);
+        assert_eq!(brush.radius, 64);
+    }
+}
@@ -0,0 +1,101 @@
+use std::ops::Range;
+
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 64.0;
+
+// Maps the board onto the window. Board coordinates are in tiles, window ones in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Camera {
+    // Board position shown in the top-left corner of the window.
+    pub(crate) x: f32,
+    pub(crate) y: f32,
+    // Window pixels per tile.
+    pub(crate) zoom: f32,
+    pub(crate) width: f32,
+    pub(crate) height: f32,
+}
+
+impl Camera {
+    pub(crate) fn new(zoom: f32, width: f32, height: f32) -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            zoom,
+            width,
+            height,
+        }
+    }
+
+    pub(crate) fn board_point(&self, x: f32, y: f32) -> (f32, f32) {
+        (self.x + x / self.zoom, self.y + y / self.zoom)
+    }
+
+    pub(crate) fn window_point(&self, x: f32, y: f32) -> (f32, f32) {
+        ((x - self.x) * self.zoom, (y - self.y) * self.zoom)
+    }
+
+    // Columns and rows of the tiles which are at least partly visible.
+    pub(crate) fn visible(&self, width: usize, height: usize) -> (Range<usize>, Range<usize>) {
+        let (left, top) = self.board_point(0.0, 0.0);
+        let (right, bottom) = self.board_point(self.width, self.height);
+        let clip = |from: f32, to: f32, size: usize| {
+            (from.floor().max(0.0) as usize).min(size)..(to.ceil().max(0.0) as usize).min(size)
+        };
+        (clip(left, right, width), clip(top, bottom, height))
+    }
+
+    // The board point under the cursor stays where it is.
+    pub(crate) fn zoom_at(&mut self, x: f32, y: f32, factor: f32) {
+        let (board_x, board_y) = self.board_point(x, y);
+        self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.x = board_x - x / self.zoom;
+        self.y = board_y - y / self.zoom;
+    }
+
+    // Distances are in window pixels.
+    pub(crate) fn pan(&mut self, dx: f32, dy: f32) {
+        self.x += dx / self.zoom;
+        self.y += dy / self.zoom;
+    }
+
+    // Keeps the board in sight. A board smaller than the window stays in its middle.
+    pub(crate) fn keep_in_sight(&mut self, width: usize, height: usize) {
+        let fit = |position: f32, board: usize, window: f32| {
+            let (board, view) = (board as f32, window / self.zoom);
+            if view >= board {
+                (board - view) / 2.0
+            } else {
+                position.clamp(0.0, board - view)
+            }
+        };
+        self.x = fit(self.x, width, self.width);
+        self.y = fit(self.y, height, self.height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Camera;
+
+    #[test]
+    fn zooms_around_the_cursor() {
+        let mut camera = Camera::new(4.0, 800.0, 600.0);
+        camera.pan(400.0, 0.0);
+        let before = camera.board_point(200.0, 100.0);
+        camera.zoom_at(200.0, 100.0, 2.0);
+        assert_eq!(camera.zoom, 8.0);
+        assert_eq!(camera.board_point(200.0, 100.0), before);
+        assert_eq!(camera.window_point(before.0, before.1), (200.0, 100.0));
+        assert_eq!(camera.visible(1000, 1000), (125..225, 12..88));
+    }
+
+    #[test]
+    fn keeps_the_board_in_sight() {
+        let mut camera = Camera::new(4.0, 800.0, 600.0);
+        camera.pan(-100.0, 100_000.0);
+        camera.keep_in_sight(1000, 100);
+        // Wider than the window, so it stops at the edge. Lower, so it's in the middle.
+        assert_eq!((camera.x, camera.y), (0.0, -25.0));
+        assert_eq!(camera.visible(1000, 100), (0..200, 0..100));
+    }
+}
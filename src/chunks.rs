@@ -0,0 +1,166 @@
+// Side of the square chunks the world is split into. A row of a chunk fits in a single word.
+pub(crate) const CHUNK_SIZE: usize = 64;
+
+// Values for every tile of the world, stored in chunks which are only allocated once something
+// other than the default is written there. Big worlds are mostly empty, so the bookkeeping
+// only costs memory where something happens.
+#[derive(Clone, Debug)]
+pub(crate) struct ChunkGrid<T> {
+    width: usize,
+    height: usize,
+    chunks_x: usize,
+    chunks: Vec<Option<Box<[T]>>>,
+}
+
+impl<T: Copy + Default> ChunkGrid<T> {
+    pub(crate) fn new(width: usize, height: usize) -> Self {
+        let chunks_x = width.div_ceil(CHUNK_SIZE);
+        Self {
+            width,
+            height,
+            chunks_x,
+            chunks: vec![None; chunks_x * height.div_ceil(CHUNK_SIZE)],
+        }
+    }
+
+    pub(crate) fn get(&self, x: usize, y: usize) -> T {
+        if x >= self.width || y >= self.height {
+            return T::default();
+        }
+        self.chunks[self.chunk(x, y)]
+            .as_ref()
+            .map_or_else(T::default, |chunk| chunk[Self::offset(x, y)])
+    }
+
+    pub(crate) fn set(&mut self, x: usize, y: usize, value: T) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let chunk = self.chunk(x, y);
+        let chunk = self.chunks[chunk]
+            .get_or_insert_with(|| vec![T::default(); CHUNK_SIZE * CHUNK_SIZE].into_boxed_slice());
+        chunk[Self::offset(x, y)] = value;
+    }
+
+    // Back to defaults everywhere, memory of the chunks is released.
+    pub(crate) fn clear(&mut self) {
+        self.chunks.fill(None);
+    }
+
+    fn chunk(&self, x: usize, y: usize) -> usize {
+        (y / CHUNK_SIZE) * self.chunks_x + x / CHUNK_SIZE
+    }
+
+    fn offset(x: usize, y: usize) -> usize {
+        (y % CHUNK_SIZE) * CHUNK_SIZE + x % CHUNK_SIZE
+    }
+}
+
+// Chunks in which something may still move. Whatever changes wakes up its chunk together with
+// the neighboring ones, since the tiles next to the change may be able to move now. Chunks in
+// which nothing changed during a whole tick fall asleep.
+#[derive(Clone, Debug)]
+pub(crate) struct Activity {
+    chunks_x: usize,
+    chunks_y: usize,
+    // Awake for the current tick.
+    awake: Vec<bool>,
+    // Changed during the current tick.
+    changed: Vec<bool>,
+}
+
+impl Activity {
+    // Everything starts awake.
+    pub(crate) fn new(width: usize, height: usize) -> Self {
+        let (chunks_x, chunks_y) = (width.div_ceil(CHUNK_SIZE), height.div_ceil(CHUNK_SIZE));
+        Self {
+            chunks_x,
+            chunks_y,
+            awake: vec![true; chunks_x * chunks_y],
+            changed: vec![false; chunks_x * chunks_y],
+        }
+    }
+
+    pub(crate) fn wake(&mut self, x: usize, y: usize) {
+        if let Some(chunk) = self.chunk(x, y) {
+            self.changed[chunk] = true;
+        }
+    }
+
+    // Chunk of the tile is awake, either since the start of the tick or because
+    // something changed next to it during this tick.
+    pub(crate) fn is_awake(&self, x: usize, y: usize) -> bool {
+        let (cx, cy) = (x / CHUNK_SIZE, y / CHUNK_SIZE);
+        self.chunk(x, y).is_some_and(|chunk| self.awake[chunk])
+            || self
+                .around(cx, cy)
+                .any(|(cx, cy)| self.changed[cy * self.chunks_x + cx])
+    }
+
+    // Changes of the finished tick decide what is awake in the next one.
+    pub(crate) fn next_tick(&mut self) {
+        self.awake.fill(false);
+        for cy in 0..self.chunks_y {
+            for cx in 0..self.chunks_x {
+                if self.changed[cy * self.chunks_x + cx] {
+                    for (x, y) in self.around(cx, cy).collect::<Vec<_>>() {
+                        self.awake[y * self.chunks_x + x] = true;
+                    }
+                }
+            }
+        }
+        self.changed.fill(false);
+    }
+
+    pub(crate) fn awake_count(&self) -> usize {
+        self.awake.iter().filter(|awake| **awake).count()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.awake.len()
+    }
+
+    fn chunk(&self, x: usize, y: usize) -> Option<usize> {
+        let (cx, cy) = (x / CHUNK_SIZE, y / CHUNK_SIZE);
+        (cx < self.chunks_x && cy < self.chunks_y).then(|| cy * self.chunks_x + cx)
+    }
+
+    // The chunk itself and its neighbors, diagonal ones included.
+    fn around(&self, cx: usize, cy: usize) -> impl Iterator<Item = (usize, usize)> {
+        let (chunks_x, chunks_y) = (self.chunks_x, self.chunks_y);
+        (cy.saturating_sub(1)..(cy + 2).min(chunks_y))
+            .flat_map(move |y| (cx.saturating_sub(1)..(cx + 2).min(chunks_x)).map(move |x| (x, y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Activity, ChunkGrid, CHUNK_SIZE};
+
+    #[test]
+    fn allocates_chunks_on_write() {
+        let mut grid: ChunkGrid<Option<usize>> = ChunkGrid::new(1000, 300);
+        grid.set(999, 299, Some(7));
+        grid.set(1000, 0, Some(8));
+        assert_eq!(grid.get(999, 299), Some(7));
+        assert_eq!(grid.get(998, 299), None);
+        assert_eq!(grid.get(1000, 0), None);
+        assert_eq!(grid.chunks.iter().flatten().count(), 1);
+    }
+
+    #[test]
+    fn changes_wake_up_neighboring_chunks() {
+        let mut activity = Activity::new(5 * CHUNK_SIZE, 5 * CHUNK_SIZE);
+        activity.next_tick();
+        assert_eq!(activity.awake_count(), 0);
+
+        activity.wake(CHUNK_SIZE, CHUNK_SIZE);
+        // Right away for the neighbors, they may be next to the change.
+        assert!(activity.is_awake(0, 0));
+        assert!(!activity.is_awake(3 * CHUNK_SIZE, 0));
+        activity.next_tick();
+        assert_eq!(activity.awake_count(), 9);
+        activity.next_tick();
+        assert_eq!(activity.awake_count(), 0);
+    }
+}
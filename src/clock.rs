@@ -0,0 +1,141 @@
+use std::fmt;
+
+// Simulation speed relative to the frames drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Speed {
+    TicksPerFrame(usize),
+    FramesPerTick(usize),
+}
+
+// From the slowest to the fastest.
+const SPEEDS: [Speed; 7] = [
+    Speed::FramesPerTick(8),
+    Speed::FramesPerTick(4),
+    Speed::FramesPerTick(2),
+    Speed::TicksPerFrame(1),
+    Speed::TicksPerFrame(2),
+    Speed::TicksPerFrame(4),
+    Speed::TicksPerFrame(8),
+];
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::TicksPerFrame(1) => write!(f, "1 tick per frame"),
+            Speed::TicksPerFrame(ticks) => write!(f, "{ticks} ticks per frame"),
+            Speed::FramesPerTick(frames) => write!(f, "1 tick every {frames} frames"),
+        }
+    }
+}
+
+// Decides how many ticks are run for each frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Clock {
+    pub(crate) paused: bool,
+    speed: usize,
+    // Frames since the last tick, for the slow speeds.
+    frames: usize,
+    // Single tick asked for while paused.
+    step: bool,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: SPEEDS
+                .iter()
+                .position(|speed| *speed == Speed::TicksPerFrame(1))
+                .unwrap(),
+            frames: 0,
+            step: false,
+        }
+    }
+}
+
+impl Clock {
+    pub(crate) fn speed(&self) -> Speed {
+        SPEEDS[self.speed]
+    }
+
+    pub(crate) fn faster(&mut self) {
+        self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub(crate) fn slower(&mut self) {
+        self.speed = self.speed.saturating_sub(1);
+    }
+
+    pub(crate) fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    // Pauses a running simulation, so the tick can be looked at.
+    pub(crate) fn step(&mut self) {
+        self.paused = true;
+        self.step = true;
+    }
+
+    // Ticks to run for the frame which is about to be drawn.
+    pub(crate) fn frame(&mut self) -> usize {
+        if self.paused {
+            return std::mem::take(&mut self.step) as usize;
+        }
+        match self.speed() {
+            Speed::TicksPerFrame(ticks) => ticks,
+            Speed::FramesPerTick(frames) => {
+                self.frames += 1;
+                if self.frames < frames {
+                    return 0;
+                }
+                self.frames = 0;
+                1
+            }
+        }
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.paused {
+            write!(f, "paused (P to resume, N to step)")
+        } else {
+            write!(f, "{}", self.speed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, Speed};
+
+    #[test]
+    fn runs_ticks_at_the_chosen_speed() {
+        let mut clock = Clock::default();
+        assert_eq!(clock.frame(), 1);
+        clock.faster();
+        clock.faster();
+        assert_eq!(clock.frame(), 4);
+
+        for _ in 0..10 {
+            clock.slower();
+        }
+        assert_eq!(clock.speed(), Speed::FramesPerTick(8));
+        let ticks: usize = (0..16).map(|_| clock.frame()).sum();
+        assert_eq!(ticks, 2);
+    }
+
+    #[test]
+    fn steps_while_paused() {
+        let mut clock = Clock::default();
+        clock.toggle_pause();
+        assert_eq!(clock.frame(), 0);
+        clock.step();
+        assert_eq!(clock.frame(), 1);
+        assert_eq!(clock.frame(), 0);
+        assert!(clock.paused);
+
+        clock.toggle_pause();
+        assert_eq!(clock.frame(), 1);
+    }
+}
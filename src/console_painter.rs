@@ -40,7 +40,7 @@ impl ConsolePainter {
         for y in 0..std::cmp::min(playfield.board().height(), DRAW_LIMIT) {
             for x in 0..std::cmp::min(playfield.board().width(), DRAW_LIMIT) {
                 if let Some(blob_index) = Self::blob_index_from_point(x, y, playfield.blobs()) {
-                    let (r, g, b) = COLORS[blob_index];
+                    let (r, g, b) = COLORS[blob_index % COLORS.len()];
                     print!("{}", "o".truecolor(r, g, b))
                 } else {
                     let c = playfield.board().tiles().at(x, y);
@@ -48,6 +48,10 @@ impl ConsolePainter {
                         Some(Tile::Rock) => print!("{}", "#".black().on_white()),
                         Some(Tile::Water) => print!("{}", ",".white()),
                         Some(Tile::Air) => print!("{}", ".".bright_black()),
+                        Some(Tile::Sand) => print!("{}", "s".yellow()),
+                        Some(Tile::Oil) => print!("{}", "~".bright_yellow()),
+                        Some(Tile::Source) => print!("{}", "+".cyan()),
+                        Some(Tile::Drain) => print!("{}", "-".red()),
                         None => print!("{}", "?".magenta()),
                     }
                 }
@@ -1,190 +1,672 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use rand::{rngs::ThreadRng, seq::SliceRandom, Rng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
 
 use crate::{
-    blob_detector::BlobDetector,
-    blobs::{Blob, Blobs},
+    blob_detector::{BlobDetector, Connectivity, DetectorBackend},
+    blob_planner::{BlobPlanner, Move, Overlay},
+    blob_tracker::BlobTracker,
+    blobs::{self, Blob, Blobs},
     board::Board,
+    chunks::{Activity, CHUNK_SIZE},
     console_painter::{HasBlobs, HasBoard, Paintable},
     point::Point,
+    session::Session,
+    tiles::Tile,
 };
 
+#[derive(Clone, Default)]
+pub(crate) struct EngineConfig {
+    pub(crate) perf_check: Option<usize>,
+    // Same board and same seed give the same simulation. Random seed is picked when not provided.
+    pub(crate) seed: Option<u64>,
+    // Re-detects blobs from scratch after every tick and panics if the incrementally
+    // tracked blobs differ. Slow, meant for tests and debugging only.
+    pub(crate) check_blobs: bool,
+    // Detector used to check the tracked blobs.
+    pub(crate) detector: DetectorBackend,
+    // Whether diagonal neighbors belong to the same blob.
+    pub(crate) connectivity: Connectivity,
+    // Number of ticks between two droplets emitted by a source, 0 is the same as 1.
+    pub(crate) source_rate: usize,
+    // Counts liquid tiles around every step of the tick and records each time the amount
+    // changes unexpectedly. Slow, meant for tests and debugging only.
+    pub(crate) check_mass: bool,
+    // Blobs are planned on this many threads, 0 and 1 mean the serial tick. The parallel tick
+    // plans every blob on the board as it was at the start of the tick, while the serial one
+    // plans each blob after the earlier ones have moved. Both move the same liquid, but they
+    // don't end up with the same board. Any number of threads above 1 gives the same board.
+    pub(crate) threads: usize,
+}
+
+// Liquid appeared or disappeared where nothing should have changed its amount.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MassViolation {
+    pub(crate) tick: usize,
+    pub(crate) liquid: Tile,
+    // The blob which was being moved, none when it happened outside of the blob moves.
+    pub(crate) blob: Option<usize>,
+    pub(crate) expected: usize,
+    pub(crate) found: usize,
+}
+
+impl Display for MassViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tick {}: expected {} {:?} tiles, found {}",
+            self.tick, self.expected, self.liquid, self.found
+        )?;
+        match self.blob {
+            Some(index) => write!(f, " after moving blob {index}"),
+            None => write!(f, " outside of blob moves"),
+        }
+    }
+}
+
+// A liquid with its own blobs, different liquids never mix.
+#[derive(Clone)]
+struct Liquid {
+    tile: Tile,
+    blobs: Blobs,
+    tracker: BlobTracker,
+}
+
 #[derive(Clone)]
 pub(crate) struct Engine {
     board: Board,
-    blobs: Blobs,
-    rng: ThreadRng,
+    // Heaviest first, in the order of `Tile::LIQUIDS`.
+    liquids: Vec<Liquid>,
+    sand: BTreeSet<Point>,
+    // Only awake chunks are simulated, nothing would move in the others anyway.
+    activity: Activity,
+    // How much water each source has emitted and each drain has removed so far.
+    sources: BTreeMap<Point, usize>,
+    drains: BTreeMap<Point, usize>,
+    source_rate: usize,
+    ticks: usize,
+    rng: StdRng,
+    seed: u64,
     perf_check: Option<usize>,
     perf_data: Vec<(Duration, Duration)>,
+    // Moves and blob detection of the last tick.
+    latest_durations: (Duration, Duration),
+    // Liquid moves of the parallel tick and of the serial one on the same board.
+    perf_threads: Vec<(Duration, Duration)>,
+    threads: usize,
+    // Started on the first parallel tick, kept for the following ones.
+    pool: Option<Arc<ThreadPool>>,
+    check_blobs: bool,
+    detector: DetectorBackend,
+    connectivity: Connectivity,
+    check_mass: bool,
+    mass_violations: Vec<MassViolation>,
 }
 
 impl Engine {
-    pub(crate) fn new(board: Board, blobs: Blobs, perf_check: Option<usize>) -> Self {
+    // Blobs of both liquids are detected on the board.
+    pub(crate) fn from_board(board: Board, cfg: EngineConfig) -> Self {
+        let detect = |liquid| {
+            BlobDetector::new(&board, liquid)
+                .with_connectivity(cfg.connectivity)
+                .detect(cfg.detector)
+        };
+        let (water, oil) = (detect(Tile::Water), detect(Tile::Oil));
+        Self::new(board, water, oil, cfg)
+    }
+
+    fn new(board: Board, water: Blobs, oil: Blobs, cfg: EngineConfig) -> Self {
+        let seed = cfg.seed.unwrap_or_else(rand::random);
+        let points_of = |tile| board.tiles().positions(tile).map(|(x, y)| Point::new(x, y));
+        let sand = points_of(Tile::Sand).collect();
+        let sources = points_of(Tile::Source).map(|pt| (pt, 0)).collect();
+        let drains = points_of(Tile::Drain).map(|pt| (pt, 0)).collect();
+        let liquids = Tile::LIQUIDS
+            .into_iter()
+            .zip([water, oil])
+            .map(|(tile, blobs)| Liquid {
+                tile,
+                tracker: BlobTracker::new(board.width(), board.height(), cfg.connectivity, &blobs),
+                blobs,
+            })
+            .collect();
         Self {
+            liquids,
+            sand,
+            activity: Activity::new(board.width(), board.height()),
+            sources,
+            drains,
+            source_rate: cfg.source_rate.max(1),
+            ticks: 0,
             board,
-            blobs,
-            rng: rand::thread_rng(),
-            perf_check,
-            perf_data: perf_check.map_or(Default::default(), Vec::with_capacity),
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            perf_check: cfg.perf_check,
+            perf_data: cfg
+                .perf_check
+                .map_or(Default::default(), Vec::with_capacity),
+            latest_durations: Default::default(),
+            perf_threads: Default::default(),
+            threads: cfg.threads.max(1),
+            pool: None,
+            check_blobs: cfg.check_blobs,
+            detector: cfg.detector,
+            connectivity: cfg.connectivity,
+            check_mass: cfg.check_mass,
+            mass_violations: Default::default(),
         }
     }
 
+    // Continues a saved session. Seed of the session is used unless the config has one.
+    pub(crate) fn resume(session: Session, cfg: EngineConfig) -> Self {
+        let cfg = EngineConfig {
+            seed: cfg.seed.or(Some(session.seed)),
+            ..cfg
+        };
+        let mut engine = Self::new(session.board, session.blobs, session.oil_blobs, cfg);
+        engine.ticks = session.ticks;
+        engine.sources = session.sources;
+        engine.drains = session.drains;
+        engine
+    }
+
     fn board(&self) -> &Board {
         &self.board
     }
 
-    pub(crate) fn board_mut(&mut self) -> &mut Board {
-        &mut self.board
+    fn blobs(&self) -> &Blobs {
+        &self.liquids[0].blobs
     }
 
-    fn blobs(&self) -> &Blobs {
-        &self.blobs
+    pub(crate) fn seed(&self) -> u64 {
+        self.seed
     }
 
-    pub(crate) fn tick(&mut self) -> bool {
-        let start = Instant::now();
+    pub(crate) fn sources(&self) -> &BTreeMap<Point, usize> {
+        &self.sources
+    }
+
+    pub(crate) fn drains(&self) -> &BTreeMap<Point, usize> {
+        &self.drains
+    }
+
+    pub(crate) fn mass_violations(&self) -> &[MassViolation] {
+        &self.mass_violations
+    }
+
+    pub(crate) fn ticks(&self) -> usize {
+        self.ticks
+    }
+
+    pub(crate) fn blob_count(&self, liquid: Tile) -> usize {
+        self.liquid_index(liquid)
+            .map_or(0, |liquid| self.liquids[liquid].blobs.len())
+    }
+
+    // Taken from the tracked blobs, so the board isn't scanned.
+    pub(crate) fn tracked_amount(&self, liquid: Tile) -> usize {
+        self.liquid_index(liquid).map_or(0, |liquid| {
+            self.liquids[liquid]
+                .blobs
+                .values()
+                .map(|blob| blob.points().len())
+                .sum()
+        })
+    }
+
+    pub(crate) fn latest_durations(&self) -> (Duration, Duration) {
+        self.latest_durations
+    }
+
+    // Chunks simulated in the last tick, and all chunks of the board.
+    pub(crate) fn awake_chunks(&self) -> (usize, usize) {
+        (self.activity.awake_count(), self.activity.len())
+    }
+
+    // The RNG state itself can't be stored, so the engine re-seeds itself with a fresh seed
+    // drawn from its own RNG. Neither can the sleeping chunks, a loaded engine starts with all
+    // of them awake, so this one wakes them too. Blobs in sleeping chunks don't draw from the RNG,
+    // this way a session loaded from the snapshot continues exactly like this one.
+    pub(crate) fn snapshot(&mut self) -> Session {
+        self.reconcile();
+        self.seed = self.rng.gen();
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.activity = Activity::new(self.board.width(), self.board.height());
+        Session {
+            board: self.board.clone(),
+            blobs: self.liquids[0].blobs.clone(),
+            oil_blobs: self.liquids[1].blobs.clone(),
+            seed: self.seed,
+            ticks: self.ticks,
+            sources: self.sources.clone(),
+            drains: self.drains.clone(),
+        }
+    }
+
+    // All changes to the board made from the outside must go through here,
+    // so the blobs are kept in sync.
+    pub(crate) fn set_tile(&mut self, x: usize, y: usize, tile: Tile) {
+        let Some(current) = self.board.tiles().at(x, y).copied() else {
+            return;
+        };
+        if current == tile {
+            return;
+        }
+        for Liquid {
+            tile: liquid,
+            blobs,
+            tracker,
+        } in &mut self.liquids
+        {
+            if current == *liquid {
+                tracker.remove_point(blobs, Point::new(x, y));
+            }
+        }
+        match current {
+            Tile::Sand => {
+                self.sand.remove(&Point::new(x, y));
+            }
+            Tile::Source => {
+                self.sources.remove(&Point::new(x, y));
+            }
+            Tile::Drain => {
+                self.drains.remove(&Point::new(x, y));
+            }
+            _ => (),
+        }
+        self.board.tiles_mut().set_at(x, y, tile);
+        self.activity.wake(x, y);
+        for Liquid {
+            tile: liquid,
+            blobs,
+            tracker,
+        } in &mut self.liquids
+        {
+            if tile == *liquid {
+                tracker.insert_point(blobs, Point::new(x, y));
+            }
+        }
+        match tile {
+            Tile::Sand => {
+                self.sand.insert(Point::new(x, y));
+            }
+            Tile::Source => {
+                self.sources.insert(Point::new(x, y), 0);
+            }
+            Tile::Drain => {
+                self.drains.insert(Point::new(x, y), 0);
+            }
+            _ => (),
+        }
+    }
+
+    fn reconcile(&mut self) {
+        for Liquid { blobs, tracker, .. } in &mut self.liquids {
+            tracker.reconcile(blobs);
+        }
+    }
+
+    fn swap(&mut self, liquid: usize, index: usize, from: &Point, to: Point) {
+        self.board.swap(from.x(), from.y(), to.x(), to.y());
+        self.activity.wake(from.x(), from.y());
+        self.activity.wake(to.x(), to.y());
+        let Liquid { blobs, tracker, .. } = &mut self.liquids[liquid];
+        tracker.move_point(blobs, index, from, to);
+    }
 
-        // TODO: Quite ugly and hacky, please rewrite.
-        let mut new_blobs: BTreeMap<usize, Blob> = Default::default();
-
-        for (index, blob) in &self.blobs {
-            let mut new_points: BTreeSet<_> = Default::default();
-
-            for pt in blob.into_iter() {
-                // Try move down
-                let dest_pt = Point::new(pt.x(), pt.y() + 1);
-                let maybe_tile = self.board.tiles().at(dest_pt.x(), dest_pt.y());
-                if let Some(tile) = maybe_tile {
-                    if tile.is_air() {
-                        self.board.swap(pt.x(), pt.y(), pt.x(), pt.y() + 1);
-                        new_points.insert(dest_pt);
-                    } else {
-                        // Didn't move down, try sideways
-                        let dest_pt_left = Point::new(pt.x() - 1, pt.y());
-                        let dest_pt_right = Point::new(pt.x() + 1, pt.y());
-
-                        let maybe_tile_left =
-                            self.board.tiles().at(dest_pt_left.x(), dest_pt_left.y());
-                        let maybe_tile_right =
-                            self.board.tiles().at(dest_pt_right.x(), dest_pt_right.y());
-
-                        if let (Some(tile_left), Some(tile_right)) =
-                            (maybe_tile_left, maybe_tile_right)
-                        {
-                            match (tile_left.is_air(), tile_right.is_air()) {
-                                (true, true) => {
-                                    if self.rng.gen::<bool>() {
-                                        self.board.swap(pt.x(), pt.y(), pt.x() - 1, pt.y());
-                                        new_points.insert(Point::new(pt.x() - 1, pt.y()));
-                                    } else {
-                                        self.board.swap(pt.x(), pt.y(), pt.x() + 1, pt.y());
-                                        new_points.insert(Point::new(pt.x() + 1, pt.y()));
-                                    }
-                                }
-                                (true, false) => {
-                                    self.board.swap(pt.x(), pt.y(), pt.x() - 1, pt.y());
-                                    new_points.insert(Point::new(pt.x() - 1, pt.y()));
-                                }
-                                (false, true) => {
-                                    self.board.swap(pt.x(), pt.y(), pt.x() + 1, pt.y());
-                                    new_points.insert(Point::new(pt.x() + 1, pt.y()));
-                                }
-                                (false, false) => {
-                                    new_points.insert(pt.clone());
-                                }
-                            }
-                        }
-                    }
+    fn move_liquids(&mut self, amounts: &mut Option<Vec<usize>>) {
+        for liquid in 0..self.liquids.len() {
+            if self.threads > 1 {
+                let indices: Vec<_> = self.liquids[liquid]
+                    .blobs
+                    .iter()
+                    .filter(|(_, blob)| self.is_awake(blob))
+                    .map(|(index, _)| *index)
+                    .collect();
+                let plans = self.plan_parallel(liquid, &indices);
+                for (index, moves) in indices.into_iter().zip(plans) {
+                    self.apply(liquid, index, moves);
+                    self.verify_mass(amounts, Some(index));
+                }
+            } else {
+                let indices: Vec<_> = self.liquids[liquid].blobs.keys().copied().collect();
+                let mut overlay = Overlay::new(&self.board);
+                for index in indices {
+                    let Liquid { tile, blobs, .. } = &self.liquids[liquid];
+                    // Blobs moved earlier in this tick may have woken this one up.
+                    let Some(blob) = blobs.get(&index).filter(|blob| self.is_awake(blob)) else {
+                        continue;
+                    };
+                    let moves = BlobPlanner::new(&self.board, *tile, blob, &mut overlay)
+                        .plan(&mut self.rng);
+                    self.apply(liquid, index, moves);
+                    self.verify_mass(amounts, Some(index));
                 }
             }
+        }
+    }
 
-            new_blobs.insert(*index, Blob::new(new_points));
+    // Plans all blobs of the liquid at once, on the board as it was before any of them moved.
+    // Every blob gets its own RNG seeded in the blob order, so the result doesn't depend
+    // on the number of threads.
+    fn plan_parallel(&mut self, liquid: usize, indices: &[usize]) -> Vec<Vec<Move>> {
+        let jobs: Vec<_> = indices
+            .iter()
+            .map(|index| (*index, self.rng.gen::<u64>()))
+            .collect();
+        let threads = self.threads;
+        let pool = match &self.pool {
+            Some(pool) if pool.current_num_threads() == threads => pool.clone(),
+            _ => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .expect("unable to start planning threads");
+                self.pool.insert(Arc::new(pool)).clone()
+            }
+        };
+        let board = &self.board;
+        let Liquid { tile, blobs, .. } = &self.liquids[liquid];
+
+        pool.install(|| {
+            jobs.par_iter()
+                .map_init(
+                    || Overlay::new(board),
+                    |overlay, (index, seed)| {
+                        blobs.get(index).map_or(Vec::new(), |blob| {
+                            BlobPlanner::new(board, *tile, blob, overlay)
+                                .plan(&mut StdRng::seed_from_u64(*seed))
+                        })
+                    },
+                )
+                .collect()
+        })
+    }
+
+    // A blob which didn't move in the last tick and has nothing changing around it
+    // wouldn't move now either.
+    fn is_awake(&self, blob: &Blob) -> bool {
+        let mut last_chunk = None;
+        blob.points().iter().any(|pt| {
+            let chunk = Some((pt.x() / CHUNK_SIZE, pt.y() / CHUNK_SIZE));
+            let new_chunk = chunk != last_chunk;
+            last_chunk = chunk;
+            new_chunk && self.activity.is_awake(pt.x(), pt.y())
+        })
+    }
+
+    // Moves planned in parallel may clash with the ones of blobs applied earlier, those are dropped.
+    fn apply(&mut self, liquid: usize, index: usize, moves: Vec<Move>) {
+        for (from, to) in moves {
+            let still_here = self.liquids[liquid].tracker.owner(from.x(), from.y()) == Some(index);
+            let still_free = self
+                .board
+                .tiles()
+                .at(to.x(), to.y())
+                .is_some_and(Tile::is_air);
+            if still_here && still_free {
+                self.swap(liquid, index, &from, to);
+            }
+        }
+    }
 
-            for (_, blob) in new_blobs.iter() {
-                // No single droplet from this blob moved down, try move up.
-                let Some(top_row) = blob.points().first() else {
+    // A lighter droplet swaps places with a heavier one right above it,
+    // so the lighter liquid rises through the heavier one.
+    fn float(&mut self) {
+        for light in 1..self.liquids.len() {
+            let points: Vec<_> = self.liquids[light]
+                .blobs
+                .values()
+                .flat_map(|blob| blob.points())
+                .filter(|pt| pt.y() > 0 && self.activity.is_awake(pt.x(), pt.y()))
+                .cloned()
+                .collect();
+            for pt in points {
+                let up = Point::new(pt.x(), pt.y() - 1);
+                let Some(light_index) = self.liquids[light].tracker.owner(pt.x(), pt.y()) else {
                     continue;
                 };
-                let top_points: Vec<_> = blob
-                    .points()
-                    .iter()
-                    .filter(|pt| pt.y() == top_row.y())
-                    .collect();
-                if top_points.is_empty() {
+                let Some((heavy, heavy_index)) = (0..light).find_map(|heavy| {
+                    self.liquids[heavy]
+                        .tracker
+                        .owner(up.x(), up.y())
+                        .map(|index| (heavy, index))
+                }) else {
                     continue;
+                };
+                self.swap(light, light_index, &pt, up.clone());
+                let Liquid { blobs, tracker, .. } = &mut self.liquids[heavy];
+                tracker.move_point(blobs, heavy_index, &up, pt);
+            }
+        }
+    }
+
+    // Sand goes straight down or diagonally, so it piles up instead of levelling out.
+    // Liquids don't stop it, the grain just swaps places with them.
+    fn sand_destination(&mut self, pt: &Point) -> Option<Point> {
+        let passable = |x: usize, y: usize| {
+            self.board
+                .tiles()
+                .at(x, y)
+                .is_some_and(|tile| tile.is_air() || tile.is_liquid())
+        };
+        if passable(pt.x(), pt.y() + 1) {
+            return Some(Point::new(pt.x(), pt.y() + 1));
+        }
+
+        // Not through the gap between two tiles touching at their corners, like the steps
+        // of a diagonal wall.
+        let left = pt.x() > 0 && passable(pt.x() - 1, pt.y()) && passable(pt.x() - 1, pt.y() + 1);
+        let right = passable(pt.x() + 1, pt.y()) && passable(pt.x() + 1, pt.y() + 1);
+        match (left, right) {
+            (true, true) => {
+                if self.rng.gen::<bool>() {
+                    Some(Point::new(pt.x() - 1, pt.y() + 1))
+                } else {
+                    Some(Point::new(pt.x() + 1, pt.y() + 1))
                 }
+            }
+            (true, false) => Some(Point::new(pt.x() - 1, pt.y() + 1)),
+            (false, true) => Some(Point::new(pt.x() + 1, pt.y() + 1)),
+            (false, false) => None,
+        }
+    }
 
-                let destination_candidates: Vec<_> = blob
-                    .points()
-                    .iter()
-                    .rev()
-                    .filter(|pt| {
-                        if let Some(pt_up) = self.board.tiles().at(pt.x(), pt.y() - 1) {
-                            pt_up.is_air() && pt.y() != top_row.y()
-                        } else {
-                            false
-                        }
-                    })
-                    .map(|pt| Point::new(pt.x(), pt.y() - 1))
-                    .collect();
+    fn move_sand(&mut self, from: &Point, to: Point) {
+        self.board.swap(from.x(), from.y(), to.x(), to.y());
+        self.activity.wake(from.x(), from.y());
+        self.activity.wake(to.x(), to.y());
+        for Liquid { blobs, tracker, .. } in &mut self.liquids {
+            if let Some(index) = tracker.owner(to.x(), to.y()) {
+                // Displaced droplet takes the place of the grain.
+                tracker.move_point(blobs, index, &to, from.clone());
+            }
+        }
+        self.sand.remove(from);
+        self.sand.insert(to);
+    }
 
-                if !destination_candidates.is_empty() {
-                    let lowest_row = destination_candidates.first().unwrap().y();
-                    let lowest_candidates: Vec<_> = destination_candidates
-                        .iter()
-                        .filter(|pt| pt.y() == lowest_row)
-                        .collect();
-
-                    if !lowest_candidates.is_empty() {
-                        let top_point = top_points.choose(&mut self.rng).unwrap();
-                        let destination_point = lowest_candidates.choose(&mut self.rng).unwrap();
-
-                        self.board.swap(
-                            top_point.x(),
-                            top_point.y(),
-                            destination_point.x(),
-                            destination_point.y(),
-                        );
-                    }
+    // Below, left, right and above. Out of bounds coordinates wrap around and are rejected by the board.
+    fn neighbors(pt: &Point) -> [(usize, usize); 4] {
+        let (x, y) = (pt.x(), pt.y());
+        [
+            (x, y + 1),
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+        ]
+    }
+
+    // Each source emits a droplet into the first free neighbor, preferably below.
+    // Returns the number of emitted droplets.
+    fn emit(&mut self) -> usize {
+        if !(self.ticks - 1).is_multiple_of(self.source_rate) {
+            return 0;
+        }
+        let mut emitted = 0;
+        let sources: Vec<_> = self.sources.keys().cloned().collect();
+        for pt in sources {
+            let free = Self::neighbors(&pt)
+                .into_iter()
+                .find(|(x, y)| self.board.tiles().at(*x, *y).is_some_and(Tile::is_air));
+            if let Some((x, y)) = free {
+                self.set_tile(x, y, Tile::Water);
+                *self.sources.entry(pt).or_default() += 1;
+                emitted += 1;
+            }
+        }
+        emitted
+    }
+
+    // Each drain removes all water touching it, other liquids are left alone.
+    // Returns the number of removed tiles of each liquid.
+    fn drain(&mut self) -> Vec<usize> {
+        let mut removed = vec![0; self.liquids.len()];
+        let Some(water) = self.liquid_index(Tile::Water) else {
+            return removed;
+        };
+        let drains: Vec<_> = self.drains.keys().cloned().collect();
+        for pt in drains {
+            for (x, y) in Self::neighbors(&pt) {
+                if self.board.tiles().at(x, y) == Some(&Tile::Water) {
+                    self.set_tile(x, y, Tile::Air);
+                    *self.drains.entry(pt.clone()).or_default() += 1;
+                    removed[water] += 1;
                 }
             }
         }
+        removed
+    }
+
+    fn liquid_index(&self, tile: Tile) -> Option<usize> {
+        self.liquids.iter().position(|liquid| liquid.tile == tile)
+    }
+
+    fn liquid_amounts(&self) -> Vec<usize> {
+        self.liquids
+            .iter()
+            .map(|liquid| self.board.tiles().count(liquid.tile))
+            .collect()
+    }
+
+    // Compares the amounts of liquids on the board with the expected ones and starts expecting
+    // what has been found, so each violation is reported only once.
+    fn verify_mass(&mut self, expected: &mut Option<Vec<usize>>, blob: Option<usize>) {
+        let Some(expected) = expected.as_mut() else {
+            return;
+        };
+        let found = self.liquid_amounts();
+        for (liquid, (expected, found)) in self.liquids.iter().zip(expected.iter().zip(&found)) {
+            if expected != found {
+                let violation = MassViolation {
+                    tick: self.ticks,
+                    liquid: liquid.tile,
+                    blob,
+                    expected: *expected,
+                    found: *found,
+                };
+                eprintln!("{violation}");
+                self.mass_violations.push(violation);
+            }
+        }
+        *expected = found;
+    }
+
+    pub(crate) fn tick(&mut self) -> bool {
+        self.ticks += 1;
+        let mut amounts = self.check_mass.then(|| self.liquid_amounts());
+
+        let emitted = self.emit();
+        if let (Some(amounts), Some(water)) = (amounts.as_mut(), self.liquid_index(Tile::Water)) {
+            amounts[water] += emitted;
+        }
+        self.verify_mass(&mut amounts, None);
+
+        // Pick up any changes made from the outside since the last tick.
+        self.reconcile();
+
+        // Same moves done serially on a copy of the engine, for comparison.
+        let duration_serial = (self.perf_check.is_some() && self.threads > 1).then(|| {
+            let mut serial = self.clone();
+            serial.threads = 1;
+            let start = Instant::now();
+            serial.move_liquids(&mut None);
+            start.elapsed()
+        });
+
+        let start = Instant::now();
+
+        self.move_liquids(&mut amounts);
+        if let Some(duration_serial) = duration_serial {
+            self.perf_threads.push((start.elapsed(), duration_serial));
+        }
+        self.float();
+        self.verify_mass(&mut amounts, None);
+
+        // Bottom rows first, so the grains don't block each other.
+        let grains: Vec<_> = self.sand.iter().rev().cloned().collect();
+        for pt in grains {
+            if !self.activity.is_awake(pt.x(), pt.y()) {
+                continue;
+            }
+            if let Some(dest_pt) = self.sand_destination(&pt) {
+                self.move_sand(&pt, dest_pt);
+            }
+        }
+        self.verify_mass(&mut amounts, None);
+
+        let removed = self.drain();
+        if let Some(amounts) = amounts.as_mut() {
+            amounts
+                .iter_mut()
+                .zip(removed)
+                .for_each(|(amount, removed)| *amount -= removed);
+        }
+        self.verify_mass(&mut amounts, None);
         let duration_move = start.elapsed();
 
         let start = Instant::now();
-        // TODO: It's super inefficient to re-detect blobs each tick.
-        // Split and merge blobs as they move.
-        let mut blob_detector = BlobDetector::new(&self.board);
-        self.blobs = blob_detector.detect_quick();
+        self.reconcile();
         let duration_detector = start.elapsed();
+        self.latest_durations = (duration_move, duration_detector);
+        self.activity.next_tick();
 
-        if let Some(samples) = self.perf_check.as_mut() {
+        if self.check_blobs {
+            self.verify_blobs();
+        }
+
+        // Nothing more is sampled once the report is printed.
+        if let Some(samples) = self.perf_check.as_mut().filter(|samples| **samples > 0) {
             *samples -= 1;
             self.perf_data.push((duration_move, duration_detector));
             if *samples == 0 {
                 println!("{:?}", self.perf_data);
                 let (moves, detects): (Vec<_>, Vec<_>) = self.perf_data.clone().into_iter().unzip();
                 println!(
-                    "avg_moves={:?} avg_detects={:?}",
-                    Duration::from_millis(
-                        (moves
-                            .iter()
-                            .map(|duration| duration.as_millis() as f64)
-                            .sum::<f64>()
-                            / self.perf_data.len() as f64) as u64
-                    ),
-                    Duration::from_millis(
-                        (detects
-                            .iter()
-                            .map(|duration| duration.as_millis() as f64)
-                            .sum::<f64>()
-                            / self.perf_data.len() as f64) as u64
-                    )
+                    "seed={} avg_moves={:?} avg_detects={:?}",
+                    self.seed,
+                    average_millis(&moves),
+                    average_millis(&detects)
                 );
+                if !self.perf_threads.is_empty() {
+                    let (parallel, serial): (Vec<_>, Vec<_>) =
+                        self.perf_threads.clone().into_iter().unzip();
+                    println!(
+                        "threads={} avg_liquid_moves={:?} avg_liquid_moves_serial={:?}",
+                        self.threads,
+                        average_millis(&parallel),
+                        average_millis(&serial)
+                    );
+                }
 
                 //println!("avg={}", );
                 return true;
@@ -192,6 +674,31 @@ impl Engine {
         }
         false
     }
+
+    fn verify_blobs(&self) {
+        for liquid in &self.liquids {
+            let mut blob_detector =
+                BlobDetector::new(&self.board, liquid.tile).with_connectivity(self.connectivity);
+            let detected = blob_detector.detect(self.detector);
+            assert!(
+                blobs::same_partition(&liquid.blobs, &detected),
+                "incrementally tracked {:?} blobs ({}) differ from detected blobs ({})",
+                liquid.tile,
+                liquid.blobs.len(),
+                detected.len()
+            );
+        }
+    }
+}
+
+fn average_millis(durations: &[Duration]) -> Duration {
+    Duration::from_millis(
+        (durations
+            .iter()
+            .map(|duration| duration.as_millis() as f64)
+            .sum::<f64>()
+            / durations.len() as f64) as u64,
+    )
 }
 
 impl HasBlobs for Engine {
@@ -207,3 +714,583 @@ impl HasBoard for Engine {
 }
 
 impl Paintable for Engine {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{blob_detector::Connectivity, board::Board, tiles::Tile, tools};
+
+    use super::{Engine, EngineConfig, MassViolation};
+
+    fn checked_engine(board: Board) -> Engine {
+        checked_engine_with_connectivity(board, Connectivity::Four)
+    }
+
+    fn checked_engine_with_connectivity(board: Board, connectivity: Connectivity) -> Engine {
+        Engine::from_board(
+            board,
+            EngineConfig {
+                check_blobs: true,
+                check_mass: true,
+                connectivity,
+                ..Default::default()
+            },
+        )
+    }
+
+    // Walled board filled with random tiles, with a source and a drain here and there.
+    fn random_board(rng: &mut StdRng) -> Board {
+        let (width, height) = (rng.gen_range(5..20), rng.gen_range(5..14));
+        let mut board = Board::new(width, height);
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let tile = match rng.gen_range(0..100) {
+                    0..=49 => Tile::Air,
+                    50..=69 => Tile::Water,
+                    70..=79 => Tile::Oil,
+                    80..=87 => Tile::Sand,
+                    88..=97 => Tile::Rock,
+                    98 => Tile::Source,
+                    _ => Tile::Drain,
+                };
+                board.tiles_mut().set_at(x, y, tile);
+            }
+        }
+        board
+    }
+
+    fn liquid_total(engine: &Engine) -> usize {
+        engine.liquid_amounts().iter().sum()
+    }
+
+    #[test]
+    fn tracks_blobs_incrementally() {
+        const TILES: &str = "################\
+                             #..oooo........#\
+                             #..oooo...oo...#\
+                             #.........oo...#\
+                             #....#.........#\
+                             #...###....o...#\
+                             #..............#\
+                             #.####...####..#\
+                             #..............#\
+                             #..oo......o...#\
+                             #..............#\
+                             ################";
+        let mut engine = checked_engine(Board::new_from_str(16, 12, TILES));
+
+        // Blob check panics as soon as tracking diverges from detection.
+        for _ in 0..200 {
+            engine.tick();
+        }
+    }
+
+    #[test]
+    fn splits_blob_on_obstacle() {
+        const TILES: &str = "#######\
+                             #ooooo#\
+                             #..#..#\
+                             #..#..#\
+                             #######";
+        let mut engine = checked_engine(Board::new_from_str(7, 5, TILES));
+        assert_eq!(engine.liquids[0].blobs.len(), 1);
+
+        engine.tick();
+
+        // The pillar cuts the blob, the biggest part keeps the original index.
+        assert!(engine.liquids[0].blobs.len() > 1);
+        assert!(engine.liquids[0].blobs.contains_key(&0));
+    }
+
+    #[test]
+    fn merges_blobs_with_stable_indices() {
+        const TILES: &str = "######\
+                             #.oo.#\
+                             #....#\
+                             #....#\
+                             #oooo#\
+                             ######";
+        let mut engine = checked_engine(Board::new_from_str(6, 6, TILES));
+        assert_eq!(
+            engine.liquids[0]
+                .blobs
+                .keys()
+                .copied()
+                .collect::<BTreeSet<_>>(),
+            [0, 1].into()
+        );
+
+        for _ in 0..10 {
+            engine.tick();
+        }
+
+        assert_eq!(
+            engine.liquids[0]
+                .blobs
+                .keys()
+                .copied()
+                .collect::<BTreeSet<_>>(),
+            [0].into()
+        );
+        assert_eq!(engine.liquids[0].blobs[&0].points().len(), 6);
+    }
+
+    #[test]
+    fn same_seed_gives_same_simulation() {
+        const TILES: &str = "############\
+                             #..oooo....#\
+                             #..oooo....#\
+                             #..........#\
+                             #....##....#\
+                             #..........#\
+                             #.o......oo#\
+                             ############";
+        let board = Board::new_from_str(12, 8, TILES);
+        let run = |seed| {
+            let mut engine = Engine::from_board(
+                board.clone(),
+                EngineConfig {
+                    seed: Some(seed),
+                    ..Default::default()
+                },
+            );
+            (0..30)
+                .map(|_| {
+                    engine.tick();
+                    engine.board.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn sand_piles_up() {
+        let mut engine = checked_engine(Board::new(15, 12));
+        for _ in 0..25 {
+            engine.set_tile(7, 1, Tile::Sand);
+            engine.tick();
+            engine.tick();
+        }
+        for _ in 0..50 {
+            engine.tick();
+        }
+
+        let heights: Vec<_> = (1..14)
+            .map(|x| {
+                (1..11)
+                    .filter(|y| engine.board.tiles().at(x, *y) == Some(&Tile::Sand))
+                    .count()
+            })
+            .collect();
+        assert_eq!(heights.iter().sum::<usize>(), 25);
+        assert!(heights[6] > 1, "sand shouldn't level out: {heights:?}");
+        assert!(
+            heights.windows(2).all(|w| w[0].abs_diff(w[1]) <= 1),
+            "slopes steeper than 45 degrees: {heights:?}"
+        );
+    }
+
+    #[test]
+    fn sand_stays_above_diagonal_walls() {
+        let mut board = Board::new(10, 10);
+        for (x, y) in tools::line((1, 1), (8, 8)) {
+            board.tiles_mut().set_at(x, y, Tile::Rock);
+        }
+        for x in 2..9 {
+            board.tiles_mut().set_at(x, 1, Tile::Sand);
+        }
+        let mut engine = checked_engine(board);
+        for _ in 0..30 {
+            engine.tick();
+        }
+
+        assert_eq!(engine.sand.len(), 7);
+        assert!(
+            engine.sand.iter().all(|pt| pt.x() > pt.y()),
+            "sand fell through the wall:\n{}",
+            engine.board
+        );
+    }
+
+    #[test]
+    fn sand_sinks_through_water() {
+        const TILES: &str = "#####\
+                             #sss#\
+                             #ooo#\
+                             #ooo#\
+                             #####";
+        let mut engine = checked_engine(Board::new_from_str(5, 5, TILES));
+        for _ in 0..10 {
+            engine.tick();
+        }
+
+        assert_eq!(
+            engine.board.to_string(),
+            "#####\n#ooo#\n#ooo#\n#sss#\n#####\n"
+        );
+        assert!(engine.liquids[0]
+            .blobs
+            .values()
+            .flat_map(|blob| blob.points())
+            .all(|pt| engine.board.tiles().at(pt.x(), pt.y()) == Some(&Tile::Water)));
+    }
+
+    #[test]
+    fn liquids_separate_into_layers() {
+        const TILES: &str = "########\
+                             #~o~o~o#\
+                             #o~o~o~#\
+                             #~o~o~o#\
+                             #o~o~o~#\
+                             #......#\
+                             #......#\
+                             ########";
+        let mut engine = checked_engine(Board::new_from_str(8, 8, TILES));
+        for _ in 0..200 {
+            engine.tick();
+        }
+
+        let rows: Vec<_> = engine
+            .board
+            .to_string()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(
+            rows[3..7],
+            ["#~~~~~~#", "#~~~~~~#", "#oooooo#", "#oooooo#"],
+            "liquids didn't separate:\n{}",
+            engine.board
+        );
+        assert_eq!(engine.liquids[0].blobs.len(), 1);
+        assert_eq!(engine.liquids[1].blobs.len(), 1);
+    }
+
+    #[test]
+    fn sources_and_drains_count_water() {
+        const TILES: &str = "########\
+                             #..+...#\
+                             #......#\
+                             #......#\
+                             #-.....#\
+                             ########";
+        let mut engine = checked_engine(Board::new_from_str(8, 6, TILES));
+        engine.source_rate = 3;
+        for _ in 0..60 {
+            engine.tick();
+        }
+
+        let emitted: usize = engine.sources().values().sum();
+        let drained: usize = engine.drains().values().sum();
+        let water: usize = engine.liquids[0]
+            .blobs
+            .values()
+            .map(|blob| blob.points().len())
+            .sum();
+        assert_eq!(emitted, 20);
+        assert!(drained > 0);
+        assert_eq!(emitted, water + drained);
+    }
+
+    #[test]
+    fn drains_leave_oil_alone() {
+        let mut engine = checked_engine(Board::new_from_str(5, 3, "######~-o######"));
+        engine.tick();
+
+        assert_eq!(engine.liquid_amounts(), [0, 1]);
+        assert_eq!(engine.drains().values().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn conserves_mass_on_random_boards() {
+        let mut rng = StdRng::seed_from_u64(2137);
+        for _ in 0..50 {
+            let board = random_board(&mut rng);
+            let mut engine = checked_engine(board.clone());
+            engine.seed = rng.gen();
+            engine.rng = StdRng::seed_from_u64(engine.seed);
+            let initial = liquid_total(&engine);
+
+            for _ in 0..100 {
+                engine.tick();
+            }
+
+            assert!(
+                engine.mass_violations().is_empty(),
+                "seed {} broke the mass on\n{board}{:?}",
+                engine.seed,
+                engine.mass_violations()
+            );
+            let emitted: usize = engine.sources().values().sum();
+            let drained: usize = engine.drains().values().sum();
+            assert_eq!(liquid_total(&engine) + drained, initial + emitted);
+        }
+    }
+
+    #[test]
+    fn parallel_tick_does_not_depend_on_thread_count() {
+        const TILES: &str = "############\
+                             #..oooo....#\
+                             #..oooo.~~.#\
+                             #..........#\
+                             #.oo.##....#\
+                             #..........#\
+                             #.o......oo#\
+                             ############";
+        let board = Board::new_from_str(12, 8, TILES);
+        let run = |threads| {
+            let mut engine = checked_engine(board.clone());
+            engine.threads = threads;
+            engine.rng = StdRng::seed_from_u64(7);
+            (0..30)
+                .map(|_| {
+                    engine.tick();
+                    engine.board.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(2), run(5));
+    }
+
+    #[test]
+    fn parallel_tick_settles_like_serial() {
+        const TILES: &str = "################\
+                             #ooo...#...o...#\
+                             #ooo...#...o...#\
+                             #ooo...#...o...#\
+                             #ooo...#...o...#\
+                             #......#...o...#\
+                             #......#...o...#\
+                             #......#...o...#\
+                             #......#.......#\
+                             ################";
+        let board = Board::new_from_str(16, 10, TILES);
+        let (mut serial, mut parallel) = (checked_engine(board.clone()), checked_engine(board));
+        parallel.threads = 4;
+        for _ in 0..200 {
+            serial.tick();
+            parallel.tick();
+        }
+
+        // Both fill the bottom of each basin, whichever way the blobs got there.
+        let settled = "################\n\
+                       #......#.......#\n\
+                       #......#.......#\n\
+                       #......#.......#\n\
+                       #......#.......#\n\
+                       #......#.......#\n\
+                       #......#.......#\n\
+                       #oooooo#.......#\n\
+                       #oooooo#ooooooo#\n\
+                       ################\n";
+        assert_eq!(serial.board.to_string(), settled);
+        assert_eq!(parallel.board.to_string(), settled);
+    }
+
+    #[test]
+    fn parallel_tick_conserves_mass() {
+        let mut rng = StdRng::seed_from_u64(1410);
+        for _ in 0..20 {
+            let mut engine = checked_engine(random_board(&mut rng));
+            engine.threads = 3;
+            for _ in 0..50 {
+                engine.tick();
+            }
+            assert!(engine.mass_violations().is_empty());
+        }
+    }
+
+    #[test]
+    fn reports_mass_violations() {
+        const TILES: &str = "####\
+                             #oo#\
+                             ####";
+        let mut engine = checked_engine(Board::new_from_str(4, 3, TILES));
+        engine.tick();
+        assert!(engine.mass_violations().is_empty());
+
+        // Pretend a droplet vanished while blob 0 was moving.
+        engine.verify_mass(&mut Some(vec![3, 0]), Some(0));
+
+        assert_eq!(
+            engine.mass_violations(),
+            [MassViolation {
+                tick: 1,
+                liquid: Tile::Water,
+                blob: Some(0),
+                expected: 3,
+                found: 2,
+            }]
+        );
+        assert_eq!(
+            engine.mass_violations()[0].to_string(),
+            "tick 1: expected 3 Water tiles, found 2 after moving blob 0"
+        );
+    }
+
+    // Row of the highest water tile in the given columns.
+    fn water_level(engine: &Engine, columns: std::ops::Range<usize>) -> usize {
+        (0..engine.board.height())
+            .find(|y| {
+                columns
+                    .clone()
+                    .any(|x| engine.board.tiles().at(x, *y) == Some(&Tile::Water))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn u_tube_levels_out() {
+        const TILES: &str = "#########\
+                             #...#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooo#...#\
+                             #ooooooo#\
+                             #ooooooo#\
+                             #########";
+        let mut engine = checked_engine(Board::new_from_str(9, 19, TILES));
+        for _ in 0..15 {
+            engine.tick();
+        }
+
+        let (left, right) = (water_level(&engine, 1..4), water_level(&engine, 5..8));
+        assert!(
+            left.abs_diff(right) <= 1,
+            "arms didn't level out:\n{}",
+            engine.board
+        );
+        assert!(engine.mass_violations().is_empty());
+    }
+
+    #[test]
+    fn siphon_moves_water_over_the_wall() {
+        const TILES: &str = "#############\
+                             #...#####...#\
+                             #...#ooo#...#\
+                             #ooo#o#o#...#\
+                             #ooo#o#o#...#\
+                             #ooo#o#o#...#\
+                             #ooo#o#o#...#\
+                             #ooo#o#o#...#\
+                             #ooo#o#o#ooo#\
+                             #ooooo#ooooo#\
+                             #ooooo#ooooo#\
+                             #############";
+        let mut engine = checked_engine(Board::new_from_str(13, 12, TILES));
+        for _ in 0..15 {
+            engine.tick();
+        }
+
+        let (left, right) = (water_level(&engine, 1..4), water_level(&engine, 9..12));
+        assert!(
+            left.abs_diff(right) <= 1,
+            "basins didn't level out:\n{}",
+            engine.board
+        );
+        // No air gets into the tube, so it stays full the whole time.
+        assert_eq!(engine.liquids[0].blobs.len(), 1);
+    }
+
+    #[test]
+    fn water_flows_through_diagonal_gap_only_with_eight_connectivity() {
+        const TILES: &str = "##########\
+                             #...#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             #ooo#....#\
+                             ####oooo##\
+                             ##########";
+        let level_after_ticks = |connectivity| {
+            let board = Board::new_from_str(10, 13, TILES);
+            let mut engine = checked_engine_with_connectivity(board, connectivity);
+            for _ in 0..30 {
+                engine.tick();
+            }
+            assert!(engine.mass_violations().is_empty());
+            water_level(&engine, 1..4)
+        };
+
+        // Left arm only touches the water below the wall at its corner.
+        assert_eq!(level_after_ticks(Connectivity::Four), 2);
+        assert!(level_after_ticks(Connectivity::Eight) > 4);
+    }
+
+    #[test]
+    fn settled_water_sleeps_until_disturbed() {
+        // Lake filling whole rows of a board a few chunks big, it has nowhere to go.
+        let mut board = Board::new(200, 150);
+        for y in 140..149 {
+            for x in 1..199 {
+                board.tiles_mut().set_at(x, y, Tile::Water);
+            }
+        }
+        let mut engine = checked_engine(board);
+        engine.tick();
+        engine.tick();
+        assert_eq!(engine.awake_chunks(), (0, 12));
+
+        engine.set_tile(100, 10, Tile::Water);
+        engine.tick();
+        assert_eq!(engine.board.tiles().at(100, 11), Some(&Tile::Water));
+        // Its chunk and the neighboring ones, the board is only three chunks tall.
+        assert_eq!(engine.awake_chunks().0, 6);
+    }
+
+    #[test]
+    fn stops_sampling_after_perf_check() {
+        let mut engine = Engine::from_board(
+            Board::new(8, 6),
+            EngineConfig {
+                perf_check: Some(2),
+                ..Default::default()
+            },
+        );
+        let finished: Vec<_> = (0..5).map(|_| engine.tick()).collect();
+        assert_eq!(finished, [false, true, false, false, false]);
+        assert_eq!(engine.perf_data.len(), 2);
+    }
+
+    #[test]
+    fn keeps_painted_water_in_sync() {
+        let mut engine = checked_engine(Board::new(12, 10));
+        engine.set_tile(3, 3, Tile::Water);
+        engine.set_tile(4, 3, Tile::Water);
+        engine.set_tile(8, 2, Tile::Water);
+        engine.tick();
+        engine.set_tile(8, 3, Tile::Air);
+        for _ in 0..20 {
+            engine.tick();
+        }
+
+        let water: usize = engine.liquids[0]
+            .blobs
+            .values()
+            .map(|blob| blob.points().len())
+            .sum();
+        assert_eq!(water, 2);
+    }
+}
@@ -1,19 +1,42 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
 use ggez::{
     event::{self, EventHandler},
-    input::keyboard::KeyCode,
+    input::keyboard::{KeyCode, KeyMods},
     Context, GameResult,
 };
 
 use crate::{
+    brush::Brush,
+    camera::Camera,
+    clock::Clock,
     console_painter::{ConsolePainter, HasBoard},
     engine::Engine,
-    ggez_painter::GgezPainter,
+    ggez_painter::{GgezPainter, PainterBackend},
+    history::History,
+    hud::Hud,
+    palette::Palette,
+    point::Point,
+    recorder::Recorder,
     tiles::{Tile, TileUpdateOperation, TileUpdateRule},
+    tools::{self, Drag, Tool},
 };
 
 #[derive(Default)]
 pub(crate) struct GameConfig {
     pub(crate) console_preview: bool,
+    pub(crate) save_path: PathBuf,
+    pub(crate) export_path: PathBuf,
+    pub(crate) palette: Palette,
+    // Largest window, in pixels. Bigger boards are looked at through the camera.
+    // The window fits the whole board when not set.
+    pub(crate) max_window: Option<(usize, usize)>,
+    pub(crate) painter: PainterBackend,
+    // Average painting time is printed on quit.
+    pub(crate) perf_paint: bool,
 
     // TODO: Support performance meters after there is an option to load board from file,
     // so we get repetitive results.
@@ -21,11 +44,31 @@ pub(crate) struct GameConfig {
     pub(crate) _perf_blob_detect: bool,
 }
 
+// Window pixels the camera moves by with each arrow key press.
+const PAN_STEP: f32 = 32.0;
+// Zoom changes by this factor with each step of the mouse wheel.
+const ZOOM_STEP: f32 = 1.25;
+
 pub struct Renderer {
+    // Initial zoom of the camera.
     pub pixel_size: usize,
+    pub(crate) camera: Camera,
+    pub(crate) palette: Palette,
     pub left_button_down: bool,
     pub right_button_down: bool,
     pub middle_button_down: bool,
+    // Mouse pans the camera instead of painting while Space is held.
+    pub(crate) panning: bool,
+    pub(crate) brush: Brush,
+    // Last mouse position in the window, the brush outline is drawn around it.
+    pub(crate) cursor: Option<(f32, f32)>,
+    pub(crate) tool: Tool,
+    // Line or rectangle dragged out so far, drawn as a preview.
+    pub(crate) drag: Option<Drag>,
+    // Simulation speed, shown in the corner of the window.
+    pub(crate) clock: Clock,
+    // Diagnostics, when turned on with H.
+    pub(crate) hud: Option<Hud>,
     tile_to_draw: Tile,
 }
 
@@ -33,9 +76,18 @@ impl Default for Renderer {
     fn default() -> Self {
         Self {
             pixel_size: 4,
+            camera: Camera::new(4.0, 0.0, 0.0),
+            palette: Default::default(),
             left_button_down: false,
             right_button_down: false,
             middle_button_down: false,
+            panning: false,
+            brush: Default::default(),
+            cursor: None,
+            tool: Default::default(),
+            drag: None,
+            clock: Default::default(),
+            hud: None,
             tile_to_draw: Tile::Rock,
         }
     }
@@ -45,61 +97,145 @@ pub(crate) struct Game {
     engine: Engine,
     cfg: GameConfig,
     renderer: Renderer,
+    painter: GgezPainter,
+    // Time spent painting, reported when the game quits.
+    frames: usize,
+    paint_time: Duration,
+    // Strokes painted with the mouse, for undo and redo.
+    history: History,
+    recorder: Option<Recorder>,
 }
 
 impl Game {
-    pub(crate) fn new(engine: Engine, cfg: GameConfig) -> Self {
-        Self {
+    pub(crate) fn new(engine: Engine, cfg: GameConfig, recorder: Option<Recorder>) -> Self {
+        let mut game = Self {
             engine,
+            recorder,
+            renderer: Renderer {
+                palette: cfg.palette.clone(),
+                ..Default::default()
+            },
+            painter: GgezPainter::new(cfg.painter),
+            frames: 0,
+            paint_time: Default::default(),
+            history: Default::default(),
             cfg,
-            renderer: Default::default(),
-        }
+        };
+        let (width, height) = game.windows_size();
+        let zoom = game.renderer.pixel_size as f32;
+        game.renderer.camera = Camera::new(zoom, width as f32, height as f32);
+        game.move_camera(|_| ());
+        game
     }
 
     pub(crate) fn windows_size(&self) -> (usize, usize) {
-        (
-            self.renderer.pixel_size * self.engine.board().width(),
-            self.renderer.pixel_size * self.engine.board().height(),
-        )
+        let board = self.engine.board();
+        let (width, height) = (
+            self.renderer.pixel_size * board.width(),
+            self.renderer.pixel_size * board.height(),
+        );
+        match self.cfg.max_window {
+            Some((max_width, max_height)) => (width.min(max_width), height.min(max_height)),
+            None => (width, height),
+        }
     }
 
-    fn update_tile(&mut self, x: usize, y: usize, op: &TileUpdateOperation) {
-        // TODO: No magic numbers
+    fn move_camera(&mut self, change: impl FnOnce(&mut Camera)) {
+        let camera = &mut self.renderer.camera;
+        change(camera);
+        camera.keep_in_sight(self.engine.board().width(), self.engine.board().height());
+    }
+
+    // Board tile under the given window point.
+    fn board_tile(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        let (x, y) = self.renderer.camera.board_point(x, y);
+        let board = self.engine.board();
+        (x >= 0.0 && y >= 0.0 && (x as usize) < board.width() && (y as usize) < board.height())
+            .then_some((x as usize, y as usize))
+    }
+
+    fn update_tile(&mut self, x: f32, y: f32, op: &TileUpdateOperation) {
+        let (x, y) = self.renderer.camera.board_point(x, y);
+        let tiles = self
+            .renderer
+            .brush
+            .tiles(x.floor() as isize, y.floor() as isize);
+        self.update_tiles(tiles, op);
+    }
+
+    fn update_tiles(&mut self, tiles: Vec<(usize, usize)>, op: &TileUpdateOperation) {
         let engine = &mut self.engine;
-        let board = engine.board_mut();
-        let height = board.height();
-        let width = board.width();
-        let pixel_size = self.renderer.pixel_size;
-        let tiles = board.tiles_mut();
-        for xx in x - 10..x + 10 {
-            for yy in y - 10..y + 10 {
-                let current = tiles.at(xx / pixel_size, yy / pixel_size);
-                if Self::within_bounds(xx, yy, height, width, pixel_size)
-                    && TileUpdateRule::is_allowed(current, op)
-                {
-                    tiles.set_at(xx / pixel_size, yy / pixel_size, op.target())
+        let height = engine.board().height();
+        let width = engine.board().width();
+        for (x, y) in tiles {
+            let current = engine.board().tiles().at(x, y).copied();
+            if Self::within_bounds(x, y, height, width)
+                && TileUpdateRule::is_allowed(current.as_ref(), op)
+            {
+                if let Some(current) = current {
+                    self.history.record(Point::new(x, y), current, op.target());
                 }
+                engine.set_tile(x, y, op.target())
             }
         }
     }
 
-    fn within_bounds(x: usize, y: usize, height: usize, width: usize, pixel_size: usize) -> bool {
+    fn within_bounds(x: usize, y: usize, height: usize, width: usize) -> bool {
         // TODO: No magic numbers
-        x / pixel_size != 0
-            && x / pixel_size != width - 1
-            && y / pixel_size != 0
-            && y / pixel_size != height - 1
+        x != 0 && x < width - 1 && y != 0 && y < height - 1
+    }
+
+    // First click of a stroke. The brush paints right away, lines and rectangles
+    // wait for the button to be released.
+    fn use_tool(&mut self, x: f32, y: f32, op: TileUpdateOperation) {
+        let tool = self.renderer.tool;
+        if tool == Tool::Brush {
+            self.update_tile(x, y, &op);
+            return;
+        }
+        let Some(tile) = self.board_tile(x, y) else {
+            return;
+        };
+        match tool {
+            Tool::Fill => {
+                let tiles = tools::fill(self.engine.board(), tile.0, tile.1);
+                self.update_tiles(tiles, &op);
+            }
+            _ => {
+                self.renderer.drag = Some(Drag {
+                    tool,
+                    from: tile,
+                    to: tile,
+                    op,
+                })
+            }
+        }
     }
 
-    fn purge_tile(&mut self, x: usize, y: usize) {
+    fn purge_tile(&mut self, x: f32, y: f32) {
         self.update_tile(x, y, &TileUpdateOperation::Purge);
     }
 
-    fn erase_tile(&mut self, x: usize, y: usize) {
+    fn erase_tile(&mut self, x: f32, y: f32) {
         self.update_tile(x, y, &TileUpdateOperation::Erase);
     }
 
-    fn draw_tile(&mut self, x: usize, y: usize) {
+    fn save(&mut self) {
+        match self.engine.snapshot().save_to(&self.cfg.save_path) {
+            Ok(()) => println!("saved to {}", self.cfg.save_path.display()),
+            Err(err) => eprintln!("unable to save to {}: {err}", self.cfg.save_path.display()),
+        }
+    }
+
+    fn export(&self) {
+        let path = &self.cfg.export_path;
+        match self.engine.board().save_image(path, &self.renderer.palette) {
+            Ok(()) => println!("exported to {}", path.display()),
+            Err(err) => eprintln!("unable to export to {}: {err}", path.display()),
+        }
+    }
+
+    fn draw_tile(&mut self, x: f32, y: f32) {
         self.update_tile(
             x,
             y,
@@ -110,14 +246,49 @@ impl Game {
 
 impl EventHandler for Game {
     fn update(&mut self, ctx: &mut Context) -> GameResult {
-        if self.engine.tick() {
-            ctx.request_quit();
+        for _ in 0..self.renderer.clock.frame() {
+            let finished = self.engine.tick();
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(err) = recorder.capture(self.engine.board()) {
+                    eprintln!("unable to record, recording stopped: {err}");
+                    self.recorder = None;
+                }
+            }
+            if finished {
+                ctx.request_quit();
+                break;
+            }
         }
         Ok(())
     }
 
+    fn quit_event(&mut self, _ctx: &mut Context) -> GameResult<bool> {
+        if self.cfg.perf_paint {
+            println!(
+                "painter={:?} frames={} avg_paint={:?}",
+                self.cfg.painter,
+                self.frames,
+                self.paint_time / self.frames.max(1) as u32
+            );
+        }
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(err) = recorder.finish() {
+                eprintln!("unable to record: {err}");
+            }
+        }
+        Ok(false)
+    }
+
     fn draw(&mut self, ctx: &mut Context) -> GameResult {
-        GgezPainter::paint(&self.engine, &self.renderer, ctx).unwrap();
+        if self.renderer.hud.is_some() {
+            self.renderer.hud = Some(Hud::new(&self.engine, ctx.time.fps()));
+        }
+        let start = Instant::now();
+        self.painter
+            .paint(&self.engine, &self.renderer, ctx)
+            .unwrap();
+        self.paint_time += start.elapsed();
+        self.frames += 1;
         if self.cfg.console_preview {
             ConsolePainter::paint(&self.engine);
             println!("Press Enter for next frame");
@@ -134,20 +305,24 @@ impl EventHandler for Game {
         x: f32,
         y: f32,
     ) -> Result<(), ggez::GameError> {
-        match button {
+        let op = match button {
             event::MouseButton::Left => {
                 self.renderer.left_button_down = true;
-                self.draw_tile(x as usize, y as usize);
+                TileUpdateOperation::Paint(self.renderer.tile_to_draw)
             }
             event::MouseButton::Right => {
                 self.renderer.right_button_down = true;
-                self.erase_tile(x as usize, y as usize);
+                TileUpdateOperation::Erase
             }
             event::MouseButton::Middle => {
                 self.renderer.middle_button_down = true;
-                self.purge_tile(x as usize, y as usize);
+                TileUpdateOperation::Purge
             }
-            event::MouseButton::Other(_) => (),
+            event::MouseButton::Other(_) => return Ok(()),
+        };
+        if !self.renderer.panning {
+            self.history.begin_stroke();
+            self.use_tool(x, y, op);
         }
         Ok(())
     }
@@ -165,6 +340,15 @@ impl EventHandler for Game {
             event::MouseButton::Middle => self.renderer.middle_button_down = false,
             event::MouseButton::Other(_) => (),
         }
+        if !(self.renderer.left_button_down
+            || self.renderer.middle_button_down
+            || self.renderer.right_button_down)
+        {
+            if let Some(drag) = self.renderer.drag.take() {
+                self.update_tiles(drag.tiles(), &drag.op);
+            }
+            self.history.end_stroke();
+        }
         Ok(())
     }
 
@@ -173,31 +357,134 @@ impl EventHandler for Game {
         _ctx: &mut Context,
         x: f32,
         y: f32,
-        _dx: f32,
-        _dy: f32,
+        dx: f32,
+        dy: f32,
     ) -> Result<(), ggez::GameError> {
+        self.renderer.cursor = Some((x, y));
+        let dragging = self.renderer.left_button_down
+            || self.renderer.middle_button_down
+            || self.renderer.right_button_down;
+        if self.renderer.panning {
+            if dragging {
+                self.move_camera(|camera| camera.pan(-dx, -dy));
+            }
+            return Ok(());
+        }
+        if self.renderer.tool != Tool::Brush {
+            let tile = self.board_tile(x, y);
+            if let (Some(drag), Some(tile)) = (self.renderer.drag.as_mut(), tile) {
+                drag.to = tile;
+            }
+            return Ok(());
+        }
+
         match (
             self.renderer.left_button_down,
             self.renderer.middle_button_down,
             self.renderer.right_button_down,
         ) {
-            (true, false, false) => self.draw_tile(x as usize, y as usize),
-            (false, true, false) => self.purge_tile(x as usize, y as usize),
-            (false, false, true) => self.erase_tile(x as usize, y as usize),
+            (true, false, false) => self.draw_tile(x, y),
+            (false, true, false) => self.purge_tile(x, y),
+            (false, false, true) => self.erase_tile(x, y),
             _ => (),
         }
 
         Ok(())
     }
 
+    fn mouse_wheel_event(&mut self, ctx: &mut Context, x: f32, y: f32) -> GameResult {
+        // Shift makes the wheel resize the brush instead of zooming. Some systems turn
+        // the wheel into horizontal scrolling while Shift is held.
+        if ctx.keyboard.is_mod_active(KeyMods::SHIFT) {
+            let delta = if y != 0.0 { y } else { x };
+            if delta != 0.0 {
+                self.renderer.brush.resize(delta.signum() as isize);
+            }
+            return Ok(());
+        }
+        let cursor = ctx.mouse.position();
+        self.move_camera(|camera| camera.zoom_at(cursor.x, cursor.y, ZOOM_STEP.powf(y)));
+        Ok(())
+    }
+
+    fn key_down_event(
+        &mut self,
+        _ctx: &mut Context,
+        input: ggez::input::keyboard::KeyInput,
+        _repeated: bool,
+    ) -> Result<(), ggez::GameError> {
+        if input.mods.contains(KeyMods::CTRL) {
+            let result = match input.keycode {
+                Some(KeyCode::Z) => self
+                    .history
+                    .undo(&mut self.engine)
+                    .map_err(|err| ("undo", err)),
+                Some(KeyCode::Y) => self
+                    .history
+                    .redo(&mut self.engine)
+                    .map_err(|err| ("redo", err)),
+                _ => Ok(()),
+            };
+            if let Err((action, err)) = result {
+                eprintln!("unable to {action}: {err}");
+            }
+            return Ok(());
+        }
+        let pan = match input.keycode {
+            Some(KeyCode::Left) => (-PAN_STEP, 0.0),
+            Some(KeyCode::Right) => (PAN_STEP, 0.0),
+            Some(KeyCode::Up) => (0.0, -PAN_STEP),
+            Some(KeyCode::Down) => (0.0, PAN_STEP),
+            Some(KeyCode::Space) => {
+                self.renderer.panning = true;
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        self.move_camera(|camera| camera.pan(pan.0, pan.1));
+        Ok(())
+    }
+
     fn key_up_event(
         &mut self,
         _ctx: &mut Context,
         input: ggez::input::keyboard::KeyInput,
     ) -> Result<(), ggez::GameError> {
         match input.keycode {
+            Some(KeyCode::Space) => self.renderer.panning = false,
+            Some(KeyCode::LBracket) => self.renderer.brush.resize(-1),
+            Some(KeyCode::RBracket) => self.renderer.brush.resize(1),
+            Some(KeyCode::B) => self.renderer.brush.shape = self.renderer.brush.shape.next(),
+            Some(KeyCode::D) => self.renderer.tool = Tool::Brush,
+            Some(KeyCode::L) => self.renderer.tool = Tool::Line,
+            // Again to switch between filled and hollow ones.
+            Some(KeyCode::R) => {
+                self.renderer.tool = match self.renderer.tool {
+                    Tool::Rectangle { filled } => Tool::Rectangle { filled: !filled },
+                    _ => Tool::Rectangle { filled: true },
+                }
+            }
+            Some(KeyCode::F) => self.renderer.tool = Tool::Fill,
+            Some(KeyCode::H) => {
+                self.renderer.hud = match self.renderer.hud {
+                    Some(_) => None,
+                    None => Some(Default::default()),
+                }
+            }
+            Some(KeyCode::P) => self.renderer.clock.toggle_pause(),
+            Some(KeyCode::N) => self.renderer.clock.step(),
+            Some(KeyCode::Equals | KeyCode::Plus | KeyCode::NumpadAdd) => {
+                self.renderer.clock.faster()
+            }
+            Some(KeyCode::Minus | KeyCode::NumpadSubtract) => self.renderer.clock.slower(),
             Some(KeyCode::Key1) => self.renderer.tile_to_draw = Tile::Rock,
             Some(KeyCode::Key2) => self.renderer.tile_to_draw = Tile::Water,
+            Some(KeyCode::Key3) => self.renderer.tile_to_draw = Tile::Sand,
+            Some(KeyCode::Key4) => self.renderer.tile_to_draw = Tile::Oil,
+            Some(KeyCode::Key5) => self.renderer.tile_to_draw = Tile::Source,
+            Some(KeyCode::Key6) => self.renderer.tile_to_draw = Tile::Drain,
+            Some(KeyCode::S) => self.save(),
+            Some(KeyCode::E) => self.export(),
             _ => (),
         }
         Ok(())
@@ -1,62 +1,332 @@
+use std::ops::Range;
+
 use thiserror::Error;
 
 use ggez::{
     conf::{NumSamples, WindowMode, WindowSetup},
     event::EventLoop,
-    graphics::{self, Color, DrawMode, DrawParam, Mesh, MeshBuilder, Rect},
+    graphics::{
+        self, Color, DrawMode, DrawParam, Image, ImageFormat, Mesh, MeshBuilder, Rect, Sampler,
+        Text,
+    },
     Context, ContextBuilder,
 };
 
-use crate::{console_painter::Paintable, game::Renderer, tiles::Tile};
+use crate::{
+    board::Board, brush::BrushShape, console_painter::Paintable, game::Renderer, palette::Palette,
+    tools::Tool,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("unable to draw rectangle at ({0}, {1})")]
     UnableToDrawRectangle(usize, usize),
+    #[error("unable to draw tool overlay")]
+    ToolOverlay,
     #[error("finishing canvas failed")]
     UnableToFinishCanvasOperation,
 }
 
-pub(crate) struct GgezPainter {}
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum PainterBackend {
+    // A rectangle for every visible tile, built anew for every frame.
+    Mesh,
+    // Tile colors in a single texture, only the rows which changed are uploaded.
+    #[default]
+    Texture,
+}
+
+const BYTES_PER_PIXEL: usize = 4;
+
+// Colors of the tiles the camera sees, one pixel per tile, as they are in the texture.
+struct TileImage {
+    columns: Range<usize>,
+    rows: Range<usize>,
+    pixels: Vec<u8>,
+}
+
+impl TileImage {
+    // Fully transparent, so every row differs from the board at first.
+    fn new(columns: Range<usize>, rows: Range<usize>) -> Self {
+        Self {
+            pixels: vec![0; columns.len() * rows.len() * BYTES_PER_PIXEL],
+            columns,
+            rows,
+        }
+    }
+
+    fn row_bytes(&self) -> usize {
+        self.columns.len() * BYTES_PER_PIXEL
+    }
+
+    // Brings the colors up to date with the board. Returns the ranges of rows which changed.
+    fn update(&mut self, board: &Board, palette: &Palette) -> Vec<Range<usize>> {
+        let row_bytes = self.row_bytes();
+        let mut changed: Vec<Range<usize>> = Default::default();
+        let mut row = vec![0; row_bytes];
+        for (i, y) in self.rows.clone().enumerate() {
+            for (pixel, x) in row
+                .chunks_exact_mut(BYTES_PER_PIXEL)
+                .zip(self.columns.clone())
+            {
+                let [r, g, b] = palette.color(board.tiles().at_unchecked(x, y));
+                pixel.copy_from_slice(&[r, g, b, u8::MAX]);
+            }
+            let current = &mut self.pixels[i * row_bytes..(i + 1) * row_bytes];
+            if current == row.as_slice() {
+                continue;
+            }
+            current.copy_from_slice(&row);
+            match changed.last_mut() {
+                Some(last) if last.end == i => last.end = i + 1,
+                _ => changed.push(i..i + 1),
+            }
+        }
+        changed
+    }
+}
+
+pub(crate) struct GgezPainter {
+    backend: PainterBackend,
+    texture: Option<(TileImage, Image)>,
+}
 
 impl GgezPainter {
+    pub(crate) fn new(backend: PainterBackend) -> Self {
+        Self {
+            backend,
+            texture: None,
+        }
+    }
+
     pub(crate) fn paint<T: Paintable>(
+        &mut self,
         playfield: &T,
         renderer: &Renderer,
         ctx: &mut Context,
     ) -> Result<(), Error> {
         let mut canvas = graphics::Canvas::from_frame(ctx, Color::WHITE);
-        let pixel_size = renderer.pixel_size as f32;
+        match self.backend {
+            PainterBackend::Mesh => {
+                Self::paint_mesh(playfield.board(), renderer, ctx, &mut canvas)?
+            }
+            PainterBackend::Texture => {
+                self.paint_texture(playfield.board(), renderer, ctx, &mut canvas)
+            }
+        }
+        Self::paint_tool(renderer, ctx, &mut canvas)?;
+        // Speed of the simulation, with diagnostics under it when they are turned on.
+        let mut status = renderer.clock.to_string();
+        if let Some(hud) = &renderer.hud {
+            status = format!("{status}\n{hud}");
+        }
+        canvas.draw(
+            &Text::new(status),
+            DrawParam::default().dest([8.0, 8.0]).color(Color::BLACK),
+        );
+        canvas
+            .finish(ctx)
+            .map_err(|_| Error::UnableToFinishCanvasOperation)?;
+
+        Ok(())
+    }
 
+    fn paint_mesh(
+        board: &Board,
+        renderer: &Renderer,
+        ctx: &mut Context,
+        canvas: &mut graphics::Canvas,
+    ) -> Result<(), Error> {
+        let mesh_builder = Self::build_mesh(board, renderer)?;
+        let mesh = Mesh::from_data(ctx, mesh_builder.build());
+        canvas.draw(&mesh, DrawParam::default());
+        Ok(())
+    }
+
+    // Preview of a dragged line or rectangle, or outline of what a click would paint.
+    fn paint_tool(
+        renderer: &Renderer,
+        ctx: &mut Context,
+        canvas: &mut graphics::Canvas,
+    ) -> Result<(), Error> {
+        let Some((x, y)) = renderer.cursor else {
+            return Ok(());
+        };
+        if renderer.panning {
+            return Ok(());
+        }
+        let camera = renderer.camera;
+        let mut mesh_builder = MeshBuilder::default();
+        match renderer.drag {
+            Some(drag) => {
+                let [r, g, b] = renderer.palette.color(drag.op.target());
+                let color = Color::from_rgb(r, g, b);
+                match drag.tool {
+                    // Could be a lot of tiles, so it's a single rectangle.
+                    Tool::Rectangle { filled: true } => {
+                        let (left, top) = (drag.from.0.min(drag.to.0), drag.from.1.min(drag.to.1));
+                        let (right, bottom) =
+                            (drag.from.0.max(drag.to.0), drag.from.1.max(drag.to.1));
+                        let (x, y) = camera.window_point(left as f32, top as f32);
+                        let (width, height) = (right - left + 1, bottom - top + 1);
+                        mesh_builder
+                            .rectangle(
+                                DrawMode::fill(),
+                                Rect::new(
+                                    x,
+                                    y,
+                                    width as f32 * camera.zoom,
+                                    height as f32 * camera.zoom,
+                                ),
+                                color,
+                            )
+                            .map_err(|_| Error::ToolOverlay)?;
+                    }
+                    _ => {
+                        for (x, y) in drag.tiles() {
+                            let (x, y) = camera.window_point(x as f32, y as f32);
+                            mesh_builder
+                                .rectangle(
+                                    DrawMode::fill(),
+                                    Rect::new(x, y, camera.zoom, camera.zoom),
+                                    color,
+                                )
+                                .map_err(|_| Error::ToolOverlay)?;
+                        }
+                    }
+                }
+            }
+            None => {
+                let (x, y) = camera.board_point(x, y);
+                let (x, y) = (x.floor(), y.floor());
+                // Other tools start at a single tile.
+                let shape = match renderer.tool {
+                    Tool::Brush => renderer.brush.shape,
+                    _ => BrushShape::Pixel,
+                };
+                let radius = match shape {
+                    BrushShape::Pixel => 0.0,
+                    _ => renderer.brush.radius as f32,
+                };
+                let (left, top) = camera.window_point(x - radius, y - radius);
+                let size = (2.0 * radius + 1.0) * camera.zoom;
+                match shape {
+                    BrushShape::Circle => mesh_builder.circle(
+                        DrawMode::stroke(1.0),
+                        [left + size / 2.0, top + size / 2.0],
+                        size / 2.0,
+                        0.5,
+                        Color::BLACK,
+                    ),
+                    BrushShape::Square | BrushShape::Pixel => mesh_builder.rectangle(
+                        DrawMode::stroke(1.0),
+                        Rect::new(left, top, size, size),
+                        Color::BLACK,
+                    ),
+                }
+                .map_err(|_| Error::ToolOverlay)?;
+            }
+        }
+        let mesh = Mesh::from_data(ctx, mesh_builder.build());
+        canvas.draw(&mesh, DrawParam::default());
+        Ok(())
+    }
+
+    fn build_mesh(board: &Board, renderer: &Renderer) -> Result<MeshBuilder, Error> {
+        let camera = renderer.camera;
+
+        // Only the tiles the camera sees.
+        let (columns, rows) = camera.visible(board.width(), board.height());
         let mut mesh_builder = MeshBuilder::default();
-        for y in 0..playfield.board().height() {
-            for x in 0..playfield.board().width() {
+        for y in rows {
+            for x in columns.clone() {
+                let (window_x, window_y) = camera.window_point(x as f32, y as f32);
                 mesh_builder
                     .rectangle(
                         DrawMode::fill(),
-                        Rect::new(
-                            x as f32 * pixel_size,
-                            y as f32 * pixel_size,
-                            pixel_size,
-                            pixel_size,
-                        ),
-                        match playfield.board().tiles().at(x, y) {
-                            Some(Tile::Rock) => Color::BLACK,
-                            Some(Tile::Water) => Color::BLUE,
-                            Some(Tile::Air) => Color::WHITE,
+                        Rect::new(window_x, window_y, camera.zoom, camera.zoom),
+                        match board.tiles().at(x, y) {
+                            Some(tile) => {
+                                let [r, g, b] = renderer.palette.color(*tile);
+                                Color::from_rgb(r, g, b)
+                            }
                             None => Color::MAGENTA,
                         },
                     )
                     .map_err(|_| Error::UnableToDrawRectangle(x, y))?;
             }
         }
-        let mesh = Mesh::from_data(ctx, mesh_builder.build());
-        canvas.draw(&mesh, DrawParam::default());
-        canvas
-            .finish(ctx)
-            .map_err(|_| Error::UnableToFinishCanvasOperation)?;
+        Ok(mesh_builder)
+    }
 
-        Ok(())
+    fn paint_texture(
+        &mut self,
+        board: &Board,
+        renderer: &Renderer,
+        ctx: &mut Context,
+        canvas: &mut graphics::Canvas,
+    ) {
+        let camera = renderer.camera;
+        let (columns, rows) = camera.visible(board.width(), board.height());
+        if columns.is_empty() || rows.is_empty() {
+            return;
+        }
+
+        let (tiles, image) = match self.texture.take() {
+            Some((mut tiles, image)) if tiles.columns == columns && tiles.rows == rows => {
+                let row_bytes = tiles.row_bytes();
+                for changed in tiles.update(board, &renderer.palette) {
+                    ctx.gfx.wgpu().queue.write_texture(
+                        wgpu::ImageCopyTexture {
+                            texture: image.wgpu().0,
+                            mip_level: 0,
+                            origin: wgpu::Origin3d {
+                                x: 0,
+                                y: changed.start as u32,
+                                z: 0,
+                            },
+                            aspect: wgpu::TextureAspect::All,
+                        },
+                        &tiles.pixels[changed.start * row_bytes..changed.end * row_bytes],
+                        wgpu::ImageDataLayout {
+                            offset: 0,
+                            bytes_per_row: Some(row_bytes as u32),
+                            rows_per_image: None,
+                        },
+                        wgpu::Extent3d {
+                            width: tiles.columns.len() as u32,
+                            height: changed.len() as u32,
+                            depth_or_array_layers: 1,
+                        },
+                    );
+                }
+                (tiles, image)
+            }
+            // The camera moved, so everything is uploaded again.
+            _ => {
+                let mut tiles = TileImage::new(columns, rows);
+                tiles.update(board, &renderer.palette);
+                let image = Image::from_pixels(
+                    ctx,
+                    &tiles.pixels,
+                    ImageFormat::Rgba8UnormSrgb,
+                    tiles.columns.len() as u32,
+                    tiles.rows.len() as u32,
+                );
+                (tiles, image)
+            }
+        };
+
+        // Sharp tiles, no blurring between them.
+        canvas.set_sampler(Sampler::nearest_clamp());
+        let (x, y) = camera.window_point(tiles.columns.start as f32, tiles.rows.start as f32);
+        canvas.draw(
+            &image,
+            DrawParam::default()
+                .dest([x, y])
+                .scale([camera.zoom, camera.zoom]),
+        );
+        self.texture = Some((tiles, image));
     }
 
     pub(crate) fn init(
@@ -77,3 +347,78 @@ impl GgezPainter {
             .expect("aieee, could not create ggez context!")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{
+        board::{Board, ColorMatching},
+        camera::Camera,
+        game::Renderer,
+        palette::Palette,
+        tiles::Tile,
+    };
+
+    use super::{GgezPainter, TileImage};
+
+    #[test]
+    fn reports_only_changed_rows() {
+        let mut board = Board::new(10, 8);
+        let palette = Palette::default();
+        let mut tiles = TileImage::new(2..10, 1..8);
+        // Everything is new at first.
+        let rows: Vec<_> = tiles
+            .update(&board, &palette)
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(rows, (0..7).collect::<Vec<_>>());
+        assert!(tiles.update(&board, &palette).is_empty());
+
+        for (x, y) in [(5, 2), (1, 4), (3, 5), (4, 6)] {
+            board.tiles_mut().set_at(x, y, Tile::Water);
+        }
+        // Column 1 is not visible, rows are counted from the top of the image.
+        assert_eq!(tiles.update(&board, &palette), [1..2, 4..6]);
+    }
+
+    #[test]
+    #[ignore = "timing comparison, run with --release --ignored --nocapture"]
+    fn compare_painters_on_big_picture() {
+        let mut board = Board::from_image(
+            "resources/woter_big.png",
+            &Palette::default(),
+            ColorMatching::Strict,
+        )
+        .unwrap();
+        let mut renderer = Renderer::default();
+        renderer.camera = Camera::new(1.0, board.width() as f32, board.height() as f32);
+        let (width, height) = (board.width(), board.height());
+
+        let start = Instant::now();
+        let mesh = GgezPainter::build_mesh(&board, &renderer).unwrap();
+        let vertices = mesh.build().vertices.len();
+        let duration_mesh = start.elapsed();
+
+        let mut tiles = TileImage::new(0..width, 0..height);
+        let start = Instant::now();
+        let uploaded = tiles.update(&board, &renderer.palette);
+        let duration_full = start.elapsed();
+
+        // Something moved in a few rows, like after a tick.
+        for x in 1..width - 1 {
+            board.tiles_mut().set_at(x, height / 2, Tile::Water);
+        }
+        let start = Instant::now();
+        let changed = tiles.update(&board, &renderer.palette);
+        let duration_changed = start.elapsed();
+
+        println!(
+            "mesh={duration_mesh:?} ({vertices} vertices) texture_full={duration_full:?} \
+             ({} rows) texture_changed={duration_changed:?} ({} rows)",
+            uploaded.iter().map(|rows| rows.len()).sum::<usize>(),
+            changed.iter().map(|rows| rows.len()).sum::<usize>(),
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{
+    board, console_painter::HasBoard, engine::Engine, palette::Palette, recorder,
+    recorder::Recorder, session,
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to write the board: {0}")]
+    Io(#[from] io::Error),
+    #[error("unable to save the session: {0}")]
+    Session(#[from] session::Error),
+    #[error("unable to export the board: {0}")]
+    Board(#[from] board::Error),
+    #[error("unable to record: {0}")]
+    Recorder(#[from] recorder::Error),
+}
+
+const SESSION_EXTENSION: &str = "wtr";
+const IMAGE_EXTENSION: &str = "png";
+
+// Runs the simulation without opening any window, for benchmarks and batch jobs.
+pub(crate) struct HeadlessRunner {
+    engine: Engine,
+    ticks: usize,
+    output: Option<PathBuf>,
+    palette: Palette,
+    recorder: Option<Recorder>,
+}
+
+impl HeadlessRunner {
+    pub(crate) fn new(
+        engine: Engine,
+        ticks: usize,
+        output: Option<PathBuf>,
+        palette: Palette,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        Self {
+            engine,
+            ticks,
+            output,
+            palette,
+            recorder,
+        }
+    }
+
+    pub(crate) fn run(mut self) -> Result<(), Error> {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.capture(self.engine.board())?;
+        }
+
+        // Ticks alone, recording is left out.
+        let mut duration = Duration::ZERO;
+        let (mut moves, mut detects) = (Duration::ZERO, Duration::ZERO);
+        let mut ticks = 0;
+        while ticks < self.ticks {
+            let start = Instant::now();
+            // Done once the performance check has all of its samples.
+            let finished = self.engine.tick();
+            duration += start.elapsed();
+            ticks += 1;
+            let (duration_move, duration_detector) = self.engine.latest_durations();
+            moves += duration_move;
+            detects += duration_detector;
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.capture(self.engine.board())?;
+            }
+            if finished {
+                break;
+            }
+        }
+        let samples = ticks.max(1) as u32;
+        println!(
+            "ticks={} total={:?} avg_tick={:?} avg_moves={:?} avg_detects={:?}",
+            ticks,
+            duration,
+            duration / samples,
+            moves / samples,
+            detects / samples
+        );
+        let (awake, chunks) = self.engine.awake_chunks();
+        println!("awake_chunks={awake}/{chunks}");
+        if !self.engine.sources().is_empty() || !self.engine.drains().is_empty() {
+            println!(
+                "emitted={} drained={}",
+                self.engine.sources().values().sum::<usize>(),
+                self.engine.drains().values().sum::<usize>()
+            );
+        }
+
+        if !self.engine.mass_violations().is_empty() {
+            println!("mass_violations={}", self.engine.mass_violations().len());
+        }
+
+        if let Some(recorder) = self.recorder {
+            recorder.finish()?;
+        }
+
+        match self.output {
+            Some(path) if path.extension().is_some_and(|ext| ext == SESSION_EXTENSION) => {
+                self.engine.snapshot().save_to(path)?
+            }
+            Some(path) if path.extension().is_some_and(|ext| ext == IMAGE_EXTENSION) => {
+                self.engine.board().save_image(path, &self.palette)?
+            }
+            Some(path) => fs::write(path, self.engine.board().to_string())?,
+            None => print!("{}", self.engine.board()),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::{console_painter::HasBoard, engine::Engine, point::Point, tiles::Tile};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("nothing to undo")]
+    NothingToUndo,
+    #[error("nothing to redo")]
+    NothingToRedo,
+    #[error("{changed} of {total} tiles of the stroke have changed since, it's dropped from the history")]
+    Changed { changed: usize, total: usize },
+}
+
+// Oldest strokes are forgotten after this many.
+const LIMIT: usize = 100;
+
+// Tiles changed by a single stroke of the mouse, with what was there before and after it.
+#[derive(Debug, Default)]
+struct Stroke {
+    changes: BTreeMap<Point, (Tile, Tile)>,
+}
+
+impl Stroke {
+    // Sets all tiles to one side of the changes, but only if all of them are still
+    // on the other side. The simulation may have moved things around in the meantime.
+    fn apply(&self, engine: &mut Engine, backwards: bool) -> Result<(), Error> {
+        // What should be there now, and what it's changed to.
+        let sides = |(before, after): (Tile, Tile)| {
+            if backwards {
+                (after, before)
+            } else {
+                (before, after)
+            }
+        };
+        let changed = self
+            .changes
+            .iter()
+            .filter(|(pt, change)| {
+                engine.board().tiles().at(pt.x(), pt.y()) != Some(&sides(**change).0)
+            })
+            .count();
+        if changed > 0 {
+            return Err(Error::Changed {
+                changed,
+                total: self.changes.len(),
+            });
+        }
+        for (pt, change) in &self.changes {
+            engine.set_tile(pt.x(), pt.y(), sides(*change).1);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    done: Vec<Stroke>,
+    undone: Vec<Stroke>,
+    current: Option<Stroke>,
+}
+
+impl History {
+    pub(crate) fn begin_stroke(&mut self) {
+        self.end_stroke();
+        self.current = Some(Default::default());
+    }
+
+    // Tiles changed outside of a stroke are not recorded.
+    pub(crate) fn record(&mut self, pt: Point, before: Tile, after: Tile) {
+        let Some(stroke) = self.current.as_mut() else {
+            return;
+        };
+        stroke
+            .changes
+            .entry(pt)
+            .and_modify(|change| change.1 = after)
+            .or_insert((before, after));
+    }
+
+    pub(crate) fn end_stroke(&mut self) {
+        let Some(mut stroke) = self.current.take() else {
+            return;
+        };
+        // Painted over and back again within the stroke.
+        stroke.changes.retain(|_, (before, after)| before != after);
+        if stroke.changes.is_empty() {
+            return;
+        }
+        self.done.push(stroke);
+        if self.done.len() > LIMIT {
+            self.done.remove(0);
+        }
+        self.undone.clear();
+    }
+
+    pub(crate) fn undo(&mut self, engine: &mut Engine) -> Result<(), Error> {
+        self.end_stroke();
+        let stroke = self.done.pop().ok_or(Error::NothingToUndo)?;
+        stroke.apply(engine, true)?;
+        self.undone.push(stroke);
+        Ok(())
+    }
+
+    pub(crate) fn redo(&mut self, engine: &mut Engine) -> Result<(), Error> {
+        self.end_stroke();
+        let stroke = self.undone.pop().ok_or(Error::NothingToRedo)?;
+        stroke.apply(engine, false)?;
+        self.done.push(stroke);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::Board,
+        console_painter::HasBoard,
+        engine::{Engine, EngineConfig},
+        point::Point,
+        tiles::Tile,
+    };
+
+    use super::{Error, History};
+
+    fn engine() -> Engine {
+        Engine::from_board(
+            Board::new(12, 10),
+            EngineConfig {
+                check_blobs: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn paint(engine: &mut Engine, history: &mut History, tiles: &[(usize, usize)], tile: Tile) {
+        history.begin_stroke();
+        for (x, y) in tiles {
+            let before = *engine.board().tiles().at(*x, *y).unwrap();
+            history.record(Point::new(*x, *y), before, tile);
+            engine.set_tile(*x, *y, tile);
+        }
+        history.end_stroke();
+    }
+
+    #[test]
+    fn undoes_and_redoes_strokes() {
+        let (mut engine, mut history) = (engine(), History::default());
+        let empty = engine.board().clone();
+        paint(&mut engine, &mut history, &[(3, 8), (4, 8)], Tile::Rock);
+        let painted = engine.board().clone();
+        // The droplet flows off the rocks right away, so its stroke can't be undone.
+        paint(&mut engine, &mut history, &[(3, 7)], Tile::Water);
+        engine.tick();
+
+        assert!(matches!(
+            history.undo(&mut engine),
+            Err(Error::Changed {
+                changed: 1,
+                total: 1
+            })
+        ));
+        for x in 1..11 {
+            engine.set_tile(x, 7, Tile::Air);
+        }
+        assert_eq!(history.undo(&mut engine), Ok(()));
+        assert_eq!(engine.board(), &empty);
+        assert_eq!(history.undo(&mut engine), Err(Error::NothingToUndo));
+
+        assert_eq!(history.redo(&mut engine), Ok(()));
+        assert_eq!(engine.board(), &painted);
+        assert_eq!(history.redo(&mut engine), Err(Error::NothingToRedo));
+    }
+
+    #[test]
+    fn new_stroke_forgets_undone_ones() {
+        let (mut engine, mut history) = (engine(), History::default());
+        paint(&mut engine, &mut history, &[(3, 8)], Tile::Rock);
+        history.undo(&mut engine).unwrap();
+        paint(&mut engine, &mut history, &[(5, 8)], Tile::Rock);
+        assert_eq!(history.redo(&mut engine), Err(Error::NothingToRedo));
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fmt, time::Duration};
+
+use crate::{engine::Engine, tiles::Tile};
+
+// Diagnostics shown over the board, refreshed with every frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Hud {
+    fps: f64,
+    tick: usize,
+    water_blobs: usize,
+    oil_blobs: usize,
+    water: usize,
+    duration_move: Duration,
+    duration_detector: Duration,
+}
+
+impl Hud {
+    pub(crate) fn new(engine: &Engine, fps: f64) -> Self {
+        let (duration_move, duration_detector) = engine.latest_durations();
+        Self {
+            fps,
+            tick: engine.ticks(),
+            water_blobs: engine.blob_count(Tile::Water),
+            oil_blobs: engine.blob_count(Tile::Oil),
+            water: engine.tracked_amount(Tile::Water),
+            duration_move,
+            duration_detector,
+        }
+    }
+}
+
+impl fmt::Display for Hud {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fps: {:.1}", self.fps)?;
+        writeln!(f, "tick: {}", self.tick)?;
+        writeln!(
+            f,
+            "blobs: {} water, {} oil",
+            self.water_blobs, self.oil_blobs
+        )?;
+        writeln!(f, "water tiles: {}", self.water)?;
+        write!(
+            f,
+            "move: {:?}, detect: {:?}",
+            self.duration_move, self.duration_detector
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        board::Board,
+        engine::{Engine, EngineConfig},
+    };
+
+    use super::Hud;
+
+    #[test]
+    fn reports_engine_state() {
+        const TILES: &str = "#######\
+                             #o...~#\
+                             #.#.o.#\
+                             #######";
+        let cfg = EngineConfig {
+            seed: Some(7),
+            ..Default::default()
+        };
+        let mut engine = Engine::from_board(Board::new_from_str(7, 4, TILES), cfg);
+        engine.tick();
+
+        let hud = Hud::new(&engine, 60.0);
+        assert_eq!(
+            (hud.tick, hud.water, hud.water_blobs, hud.oil_blobs),
+            (1, 2, 2, 1)
+        );
+        assert!(hud.to_string().starts_with("fps: 60.0\ntick: 1\n"));
+    }
+}
@@ -1,22 +1,44 @@
 mod blob_detector;
+mod blob_planner;
+mod blob_tracker;
 mod blobs;
 mod board;
+mod brush;
+mod camera;
+mod chunks;
+mod clock;
 mod console_painter;
 mod engine;
 mod game;
 mod ggez_painter;
+mod headless;
+mod history;
+mod hud;
+mod palette;
 mod point;
+mod recorder;
+mod session;
 mod tiles;
+mod tools;
 
-use clap::Parser;
+use std::path::{Path, PathBuf};
 
-use blob_detector::BlobDetector;
-use board::Board;
+use clap::{builder::RangedU64ValueParser, Parser};
 
-use engine::Engine;
+use blob_detector::{BlobDetector, Connectivity, DetectorBackend};
+use blobs::Blobs;
+use board::{Board, ColorMatching};
+use console_painter::HasBoard;
+
+use engine::{Engine, EngineConfig};
 use game::{Game, GameConfig};
 use ggez::event::{self};
-use ggez_painter::GgezPainter;
+use ggez_painter::{GgezPainter, PainterBackend};
+use headless::HeadlessRunner;
+use palette::Palette;
+use recorder::Recorder;
+use session::Session;
+use tiles::Tile;
 
 const TITLE: &str = "Przelewaj Sobie Wodziczkę";
 const AUTHOR: &str = "mgr inż. Rafał";
@@ -33,34 +55,181 @@ const PLAYFIELD_HEIGHT: usize = WINDOW_HEIGHT / PIXEL_SIZE;
 #[derive(Parser, Debug)]
 struct Args {
     /// Picture to laod.
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "board")]
     picture: Option<String>,
+    /// Maps colors of the picture which don't match any tile to the nearest one, instead of failing.
+    #[arg(long)]
+    lenient: bool,
+    /// Palette file mapping colors to tiles, one 'tile:RRGGBB[~tolerance]' entry per line.
+    #[arg(long)]
+    palette_file: Option<PathBuf>,
+    /// Palette entries, e.g. 'rock:202020,water:1030c0~8'. Applied on top of the palette file.
+    #[arg(long)]
+    palette: Option<String>,
+    /// Board saved in the native format (.wtr) to resume.
+    #[arg(short, long)]
+    board: Option<PathBuf>,
+    /// Where the board is saved when S is pressed.
+    #[arg(long, default_value = "water2.wtr")]
+    save_path: PathBuf,
+    /// Where the board is exported as PNG when E is pressed.
+    #[arg(long, default_value = "water2.png")]
+    export_path: PathBuf,
+    /// Width of the empty board, in tiles. Boards bigger than the window are shown through a viewport.
+    #[arg(long, default_value_t = PLAYFIELD_WIDTH, value_parser = board_size())]
+    width: usize,
+    /// Height of the empty board, in tiles.
+    #[arg(long, default_value_t = PLAYFIELD_HEIGHT, value_parser = board_size())]
+    height: usize,
+    /// How the board is drawn in the window. With --perf-check, average painting time is printed
+    /// on quit.
+    #[arg(long, value_enum, default_value_t = PainterBackend::Texture)]
+    painter: PainterBackend,
     /// Enables performance check. Engine will run first X frames and provide timing data on stdout.
     #[arg(short = 'c', long)]
     perf_check: Option<usize>,
+    /// Seed for the simulation. The same picture and the same seed always give the same frames.
+    #[arg(short, long)]
+    seed: Option<u64>,
+    /// Runs X ticks without opening a window, then prints timing data and the final board.
+    #[arg(long)]
+    headless: Option<usize>,
+    /// Where to write the final board in headless mode. Native format is used for .wtr files,
+    /// PNG for .png files and text for anything else. Printed on stdout when missing.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Records the simulation to an animated .gif or .png (APNG).
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Records only every Nth frame.
+    #[arg(long, default_value_t = 1)]
+    record_every: usize,
+    /// Size of a single tile in the recording, in pixels.
+    #[arg(long, default_value_t = 1)]
+    record_scale: usize,
+    /// Number of ticks between two droplets emitted by each water source.
+    #[arg(long, default_value_t = 1)]
+    source_rate: usize,
+    /// Number of threads planning the blob moves. More than one gives a different simulation than
+    /// the serial tick, though the same for any number of them. With --perf-check, the serial
+    /// tick is timed too.
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// Reports every tick in which liquid appears or disappears unexpectedly (slow).
+    #[arg(long)]
+    check_mass: bool,
+    /// Blob detection algorithm, used on startup and by --check-blobs.
+    #[arg(long, value_enum, default_value_t = DetectorBackend::Quick)]
+    detector: DetectorBackend,
+    /// Neighbors belonging to the same blob: 4 (sides only) or 8 (diagonals too).
+    #[arg(long, value_enum, default_value_t = Connectivity::Four)]
+    connectivity: Connectivity,
+    /// Verifies the incrementally tracked blobs against full blob detection after every tick (slow).
+    #[arg(long)]
+    check_blobs: bool,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let board = match args.picture {
-        Some(path) => Board::from_image(path),
-        None => Board::new(PLAYFIELD_WIDTH, PLAYFIELD_HEIGHT),
+    let palette = load_palette(args.palette_file.as_deref(), args.palette.as_deref())
+        .unwrap_or_else(|err| {
+            eprintln!("{err}");
+            std::process::exit(1);
+        });
+
+    let cfg = EngineConfig {
+        perf_check: args.perf_check,
+        seed: args.seed,
+        check_blobs: args.check_blobs,
+        detector: args.detector,
+        connectivity: args.connectivity,
+        source_rate: args.source_rate,
+        check_mass: args.check_mass,
+        threads: args.threads,
     };
 
-    //let board = Board::_new_test_1();
+    let engine = match (args.board.as_deref(), args.picture.as_deref()) {
+        (Some(path), _) => match Session::load_from(path) {
+            Ok(session) => {
+                // Blobs saved with the other connectivity don't match the board any more.
+                let blobs = redetect_if_stale(&session.board, Tile::Water, session.blobs, &args);
+                let oil_blobs =
+                    redetect_if_stale(&session.board, Tile::Oil, session.oil_blobs, &args);
+                Engine::resume(
+                    Session {
+                        blobs,
+                        oil_blobs,
+                        ..session
+                    },
+                    cfg,
+                )
+            }
+            Err(err) => {
+                eprintln!("unable to load {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        },
+        (None, picture) => {
+            let board = match picture {
+                Some(path) => {
+                    let mode = if args.lenient {
+                        ColorMatching::Lenient
+                    } else {
+                        ColorMatching::Strict
+                    };
+                    Board::from_image(path, &palette, mode).unwrap_or_else(|err| {
+                        eprintln!("unable to load {path}: {err}");
+                        std::process::exit(1);
+                    })
+                }
+                None => Board::new(args.width, args.height),
+            };
+
+            //let board = Board::_new_test_1();
+
+            Engine::from_board(board, cfg)
+        }
+    };
+    println!("seed={}", engine.seed());
 
-    let mut blob_detector = BlobDetector::new(&board);
-    let blobs = blob_detector.detect_quick();
+    // Before the simulation starts, so a recording which can't be made doesn't waste it.
+    let recorder = args.record.map(|path| {
+        let board = engine.board();
+        Recorder::new(
+            path,
+            args.record_every,
+            args.record_scale,
+            palette.clone(),
+            (board.width(), board.height()),
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("{err}");
+            std::process::exit(1);
+        })
+    });
 
-    let engine = Engine::new(board, blobs, args.perf_check);
+    if let Some(ticks) = args.headless {
+        if let Err(err) = HeadlessRunner::new(engine, ticks, args.output, palette, recorder).run() {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let game = Game::new(
         engine,
         GameConfig {
             //console_preview: true,
+            save_path: args.save_path,
+            export_path: args.export_path,
+            palette,
+            max_window: Some((WINDOW_WIDTH, WINDOW_HEIGHT)),
+            painter: args.painter,
+            perf_paint: args.perf_check.is_some(),
             ..Default::default()
         },
+        recorder,
     );
 
     let (window_width, window_height) = game.windows_size();
@@ -68,3 +237,34 @@ fn main() {
 
     event::run(ctx, event_loop, game);
 }
+
+// Walls take the outermost tiles, so there's room for at least one more.
+fn board_size() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(3..)
+}
+
+fn detect_blobs(board: &Board, liquid: Tile, args: &Args) -> Blobs {
+    BlobDetector::new(board, liquid)
+        .with_connectivity(args.connectivity)
+        .detect(args.detector)
+}
+
+fn redetect_if_stale(board: &Board, liquid: Tile, blobs: Blobs, args: &Args) -> Blobs {
+    let detected = detect_blobs(board, liquid, args);
+    if blobs::same_partition(&blobs, &detected) {
+        blobs
+    } else {
+        detected
+    }
+}
+
+fn load_palette(file: Option<&Path>, entries: Option<&str>) -> Result<Palette, palette::Error> {
+    let mut palette = Palette::default();
+    if let Some(path) = file {
+        palette = palette.with_file(path)?;
+    }
+    if let Some(entries) = entries {
+        palette = palette.with_entries(entries)?;
+    }
+    Ok(palette)
+}
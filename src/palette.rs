@@ -0,0 +1,210 @@
+use std::{fs, io, path::Path};
+
+use thiserror::Error;
+
+use crate::tiles::Tile;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to read palette: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid palette entry '{0}', expected 'tile:RRGGBB' or 'tile:RRGGBB~tolerance'")]
+    InvalidEntry(String),
+    #[error("unknown tile '{0}'")]
+    UnknownTile(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    color: [u8; 3],
+    tile: Tile,
+    tolerance: u8,
+}
+
+// Maps colors to tiles when importing pictures and tiles to colors when drawing them.
+// The first entry given for a tile is the one used for drawing.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Palette {
+    entries: Vec<Entry>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            entries: [
+                ([0, 0, 0], Tile::Rock),
+                ([255, 255, 255], Tile::Air),
+                ([0, 0, 255], Tile::Water),
+                ([194, 178, 128], Tile::Sand),
+                ([128, 96, 0], Tile::Oil),
+                ([0, 255, 255], Tile::Source),
+                ([255, 0, 0], Tile::Drain),
+            ]
+            .into_iter()
+            .map(|(color, tile)| Entry {
+                color,
+                tile,
+                tolerance: 0,
+            })
+            .collect(),
+        }
+    }
+}
+
+impl Palette {
+    // Entries are separated by commas or new lines, everything after '#' is a comment, e.g.:
+    // rock:000000, water:0000ff~16
+    // Tiles not mentioned keep their default colors.
+    pub(crate) fn with_entries(mut self, spec: &str) -> Result<Self, Error> {
+        let entries = spec
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Self::parse_entry)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.entries
+            .retain(|existing| !entries.iter().any(|entry| entry.tile == existing.tile));
+        self.entries.extend(entries);
+        Ok(self)
+    }
+
+    pub(crate) fn with_file(self, path: impl AsRef<Path>) -> Result<Self, Error> {
+        self.with_entries(&fs::read_to_string(path)?)
+    }
+
+    fn parse_entry(entry: &str) -> Result<Entry, Error> {
+        let invalid = || Error::InvalidEntry(entry.to_string());
+
+        let (tile, color) = entry.split_once(':').ok_or_else(invalid)?;
+        let tile = match tile.trim().to_lowercase().as_str() {
+            "rock" => Tile::Rock,
+            "water" => Tile::Water,
+            "air" => Tile::Air,
+            "sand" => Tile::Sand,
+            "oil" => Tile::Oil,
+            "source" => Tile::Source,
+            "drain" => Tile::Drain,
+            other => return Err(Error::UnknownTile(other.to_string())),
+        };
+        let (color, tolerance) = match color.split_once('~') {
+            Some((color, tolerance)) => (color, tolerance.trim().parse().map_err(|_| invalid())?),
+            None => (color, 0),
+        };
+        let color = color.trim();
+        if color.len() != 6 {
+            return Err(invalid());
+        }
+        let channel = |i: usize| {
+            color
+                .get(i..i + 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(invalid)
+        };
+
+        Ok(Entry {
+            color: [channel(0)?, channel(2)?, channel(4)?],
+            tile,
+            tolerance,
+        })
+    }
+
+    // Exact matches win, otherwise the closest color within its tolerance.
+    pub(crate) fn tile(&self, color: [u8; 3]) -> Option<Tile> {
+        self.entries
+            .iter()
+            .filter(|entry| Self::channel_distance(entry.color, color) <= entry.tolerance)
+            .min_by_key(|entry| Self::distance(entry.color, color))
+            .map(|entry| entry.tile)
+    }
+
+    pub(crate) fn nearest_tile(&self, color: [u8; 3]) -> Tile {
+        self.entries
+            .iter()
+            .min_by_key(|entry| Self::distance(entry.color, color))
+            .map_or(Tile::Air, |entry| entry.tile)
+    }
+
+    pub(crate) fn color(&self, tile: Tile) -> [u8; 3] {
+        let find = |entries: &[Entry]| {
+            entries
+                .iter()
+                .find(|entry| entry.tile == tile)
+                .map(|entry| entry.color)
+        };
+        find(&self.entries)
+            .or_else(|| find(&Self::default().entries))
+            .unwrap_or([255, 0, 255])
+    }
+
+    fn channel_distance(a: [u8; 3], b: [u8; 3]) -> u8 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| a.abs_diff(b))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a.abs_diff(b) as u32).pow(2))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tiles::Tile;
+
+    use super::{Error, Palette};
+
+    #[test]
+    fn maps_default_colors() {
+        let palette = Palette::default();
+
+        assert_eq!(palette.tile([0, 0, 0]), Some(Tile::Rock));
+        assert_eq!(palette.tile([255, 255, 255]), Some(Tile::Air));
+        assert_eq!(palette.tile([0, 0, 255]), Some(Tile::Water));
+        assert_eq!(palette.tile([0, 0, 254]), None);
+        assert_eq!(palette.color(Tile::Water), [0, 0, 255]);
+    }
+
+    #[test]
+    fn overrides_only_mentioned_tiles() {
+        let palette = Palette::default()
+            .with_entries(
+                "# artist palette\n\
+                 water:1030C0~8, water:2040D0\n\
+                 rock:202020",
+            )
+            .unwrap();
+
+        assert_eq!(palette.tile([0, 0, 255]), None);
+        assert_eq!(palette.tile([16, 48, 192]), Some(Tile::Water));
+        assert_eq!(palette.tile([20, 50, 198]), Some(Tile::Water));
+        assert_eq!(palette.tile([32, 64, 208]), Some(Tile::Water));
+        assert_eq!(palette.tile([32, 32, 32]), Some(Tile::Rock));
+        assert_eq!(palette.tile([255, 255, 255]), Some(Tile::Air));
+        assert_eq!(palette.color(Tile::Water), [16, 48, 192]);
+        assert_eq!(palette.nearest_tile([40, 40, 40]), Tile::Rock);
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert!(matches!(
+            Palette::default().with_entries("lava:ff0000"),
+            Err(Error::UnknownTile(_))
+        ));
+        assert!(matches!(
+            Palette::default().with_entries("water:00f"),
+            Err(Error::InvalidEntry(_))
+        ));
+        assert!(matches!(
+            Palette::default().with_entries("water:0000ff~lots"),
+            Err(Error::InvalidEntry(_))
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{board::Board, palette::Palette, tiles::Tile};
+
+// Delay between recorded frames, in hundredths of a second.
+const FRAME_DELAY: u16 = 2;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("unsupported recording format of {0}, use .gif or .png")]
+    UnsupportedFormat(PathBuf),
+    #[error("recording of {0}x{1} pixels is too large")]
+    TooLarge(usize, usize),
+    #[error("unable to encode gif: {0}")]
+    Gif(#[from] gif::EncodingError),
+    #[error("unable to encode apng: {0}")]
+    Png(#[from] png::EncodingError),
+}
+
+enum Encoder {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Apng(png::Writer<BufWriter<File>>),
+}
+
+// Turns every Nth frame of the simulation into an animated GIF or APNG.
+// Frames are written as soon as they are captured, as palette indices scaled up.
+pub(crate) struct Recorder {
+    path: PathBuf,
+    encoder: Encoder,
+    every: usize,
+    scale: usize,
+    width: usize,
+    height: usize,
+    ticks: usize,
+    frames: usize,
+}
+
+impl Recorder {
+    // Size is the one of the board, before scaling.
+    pub(crate) fn new(
+        path: PathBuf,
+        every: usize,
+        scale: usize,
+        palette: Palette,
+        (width, height): (usize, usize),
+    ) -> Result<Self, Error> {
+        let scale = scale.max(1);
+        let colors: Vec<u8> = Tile::ALL
+            .iter()
+            .flat_map(|tile| palette.color(*tile))
+            .collect();
+        let (scaled_width, scaled_height) = (width * scale, height * scale);
+        let encoder = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gif") => {
+                let (Ok(gif_width), Ok(gif_height)) =
+                    (u16::try_from(scaled_width), u16::try_from(scaled_height))
+                else {
+                    return Err(Error::TooLarge(scaled_width, scaled_height));
+                };
+                // GIF palettes must have a power of two entries, just use the full size.
+                let mut colors = colors;
+                colors.resize(256 * 3, 0);
+                let writer = BufWriter::new(File::create(&path)?);
+                let mut encoder = gif::Encoder::new(writer, gif_width, gif_height, &colors)?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Encoder::Gif(encoder)
+            }
+            Some("png") | Some("apng") => {
+                let (Ok(png_width), Ok(png_height)) =
+                    (u32::try_from(scaled_width), u32::try_from(scaled_height))
+                else {
+                    return Err(Error::TooLarge(scaled_width, scaled_height));
+                };
+                let writer = BufWriter::new(File::create(&path)?);
+                let mut encoder = png::Encoder::new(writer, png_width, png_height);
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_palette(colors);
+                // Not known yet, it's fixed in the file once the recording is finished.
+                encoder.set_animated(u32::MAX, 0)?;
+                encoder.set_frame_delay(FRAME_DELAY, 100)?;
+                Encoder::Apng(encoder.write_header()?)
+            }
+            _ => return Err(Error::UnsupportedFormat(path)),
+        };
+
+        Ok(Self {
+            path,
+            encoder,
+            every: every.max(1),
+            scale,
+            width,
+            height,
+            ticks: 0,
+            frames: 0,
+        })
+    }
+
+    pub(crate) fn capture(&mut self, board: &Board) -> Result<(), Error> {
+        self.ticks += 1;
+        if !(self.ticks - 1).is_multiple_of(self.every) {
+            return Ok(());
+        }
+
+        let frame = self.scaled(&Self::frame(board));
+        match &mut self.encoder {
+            Encoder::Gif(encoder) => {
+                // Sizes are checked when the recording starts.
+                let (width, height) = (self.width * self.scale, self.height * self.scale);
+                let mut frame =
+                    gif::Frame::from_indexed_pixels(width as u16, height as u16, &frame, None);
+                frame.delay = FRAME_DELAY;
+                encoder.write_frame(&frame)?;
+            }
+            Encoder::Apng(writer) => writer.write_image_data(&frame)?,
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        match self.encoder {
+            Encoder::Gif(encoder) => encoder.into_inner()?.flush()?,
+            Encoder::Apng(writer) => {
+                writer.finish()?;
+                write_frame_count(&self.path, self.frames as u32)?;
+            }
+        }
+        println!("recorded {} frames to {}", self.frames, self.path.display());
+        Ok(())
+    }
+
+    // Palette indices, one byte per tile.
+    fn frame(board: &Board) -> Vec<u8> {
+        (0..board.height())
+            .flat_map(|y| (0..board.width()).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let tile = board.tiles().at(x, y).copied().unwrap_or(Tile::Air);
+                Tile::ALL
+                    .iter()
+                    .position(|t| t == &tile)
+                    .unwrap_or_default() as u8
+            })
+            .collect()
+    }
+
+    fn scaled(&self, frame: &[u8]) -> Vec<u8> {
+        if self.scale == 1 {
+            return frame.to_vec();
+        }
+        frame
+            .chunks(self.width)
+            .flat_map(|row| {
+                let row: Vec<_> = row
+                    .iter()
+                    .flat_map(|index| std::iter::repeat_n(*index, self.scale))
+                    .collect();
+                std::iter::repeat_n(row, self.scale).flatten()
+            })
+            .collect()
+    }
+}
+
+// Rewrites the number of frames in the animation control chunk of a finished APNG.
+fn write_frame_count(path: &Path, frames: u32) -> Result<(), Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    // Chunks start after the signature.
+    let mut offset = 8;
+    loop {
+        let mut header = [0; 8];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        if &header[4..] == b"acTL" {
+            let mut data = [0; 8];
+            file.read_exact(&mut data)?;
+            data[..4].copy_from_slice(&frames.to_be_bytes());
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(b"acTL");
+            crc.update(&data);
+            file.seek(SeekFrom::Start(offset + 8))?;
+            file.write_all(&data)?;
+            file.write_all(&crc.finalize().to_be_bytes())?;
+            return Ok(());
+        }
+        // Length, type and CRC around the data.
+        offset += 12 + u64::from(length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{board::Board, palette::Palette};
+
+    use super::{Error, Recorder};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("water2-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn records_every_nth_frame_scaled() {
+        let path = temp_path("test.gif");
+        let mut recorder = Recorder::new(path.clone(), 2, 3, Palette::default(), (2, 2)).unwrap();
+        let board = Board::new_from_str(2, 2, "#o.#");
+
+        for _ in 0..5 {
+            recorder.capture(&board).unwrap();
+        }
+
+        assert_eq!(recorder.frames, 3);
+        assert_eq!(
+            recorder.scaled(&Recorder::frame(&board)),
+            [
+                0, 0, 0, 1, 1, 1, //
+                0, 0, 0, 1, 1, 1, //
+                0, 0, 0, 1, 1, 1, //
+                2, 2, 2, 0, 0, 0, //
+                2, 2, 2, 0, 0, 0, //
+                2, 2, 2, 0, 0, 0,
+            ]
+        );
+        recorder.finish().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fixes_frame_count_of_apng() {
+        let path = temp_path("test.png");
+        let mut recorder = Recorder::new(path.clone(), 1, 1, Palette::default(), (2, 2)).unwrap();
+        let board = Board::new_from_str(2, 2, "#o.#");
+        for _ in 0..4 {
+            recorder.capture(&board).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let decoder = png::Decoder::new(fs::File::open(&path).unwrap());
+        let reader = decoder.read_info().unwrap();
+        let animation = reader.info().animation_control.unwrap();
+        assert_eq!(animation.num_frames, 4);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_format_and_size() {
+        assert!(matches!(
+            Recorder::new(PathBuf::from("movie.avi"), 1, 1, Palette::default(), (2, 2)),
+            Err(Error::UnsupportedFormat(_))
+        ));
+        assert!(matches!(
+            Recorder::new(
+                PathBuf::from("big.gif"),
+                1,
+                4,
+                Palette::default(),
+                (20000, 10)
+            ),
+            Err(Error::TooLarge(80000, 40))
+        ));
+    }
+}
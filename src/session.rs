@@ -0,0 +1,454 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::{
+    blobs::{Blob, Blobs},
+    board::Board,
+    point::Point,
+    tiles::Tile,
+};
+
+// Native, lossless file format. Everything is little-endian:
+//
+// magic        4 bytes, "WTR2"
+// version      u32
+// width        u32
+// height       u32
+// seed         u64
+// ticks        u64, since version 3
+// tiles        width * height bytes, row by row, sources (5) and drains (6) since version 3
+// blob count   u32
+// blobs        index (u64), point count (u32), points (u32 x, u32 y)
+// oil blobs    same as blobs, since version 2
+// sources      count (u32), then u32 x, u32 y and u64 tiles emitted of each, since version 3
+// drains       same as sources with the tiles removed, since version 3
+const MAGIC: &[u8; 4] = b"WTR2";
+const VERSION: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a water2 board file")]
+    BadMagic,
+    #[error("unsupported file version {0}, the newest supported is {VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("unknown tile code {code} at ({x}, {y})")]
+    UnknownTile { x: usize, y: usize, code: u8 },
+    #[error("corrupted file: {0}")]
+    Corrupted(String),
+}
+
+// Everything needed to resume the simulation exactly where it was saved.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Session {
+    pub(crate) board: Board,
+    pub(crate) blobs: Blobs,
+    pub(crate) oil_blobs: Blobs,
+    pub(crate) seed: u64,
+    pub(crate) ticks: usize,
+    // How much each source has emitted and each drain has removed so far.
+    pub(crate) sources: BTreeMap<Point, usize>,
+    pub(crate) drains: BTreeMap<Point, usize>,
+}
+
+impl Session {
+    pub(crate) fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.save(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn load_from(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::load(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub(crate) fn save(&self, writer: &mut impl Write) -> Result<(), Error> {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+        write_u32(writer, self.board.width() as u32)?;
+        write_u32(writer, self.board.height() as u32)?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&(self.ticks as u64).to_le_bytes())?;
+
+        let mut row = Vec::with_capacity(self.board.width());
+        for y in 0..self.board.height() {
+            row.clear();
+            row.extend(
+                (0..self.board.width())
+                    .map(|x| tile_code(self.board.tiles().at(x, y).copied().unwrap_or(Tile::Air))),
+            );
+            writer.write_all(&row)?;
+        }
+
+        write_blobs(writer, &self.blobs)?;
+        write_blobs(writer, &self.oil_blobs)?;
+        write_counters(writer, &self.sources)?;
+        write_counters(writer, &self.drains)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(reader: &mut impl Read) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version = read_u32(reader)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let width = read_u32(reader)? as usize;
+        let height = read_u32(reader)? as usize;
+        let seed = read_u64(reader)?;
+        let ticks = if version >= 3 {
+            read_u64(reader)? as usize
+        } else {
+            0
+        };
+        if width == 0 || height == 0 {
+            return Err(Error::Corrupted(format!("invalid size {width}x{height}")));
+        }
+
+        let mut board = Board::new(width, height);
+        let mut row = vec![0; width];
+        for y in 0..height {
+            reader.read_exact(&mut row)?;
+            for (x, code) in row.iter().enumerate() {
+                let tile = tile_from_code(*code).ok_or(Error::UnknownTile { x, y, code: *code })?;
+                board.tiles_mut().set_at(x, y, tile);
+            }
+        }
+
+        let blobs = read_blobs(reader, &board, Tile::Water)?;
+        // Older files have no oil.
+        let oil_blobs = if version >= 2 {
+            read_blobs(reader, &board, Tile::Oil)?
+        } else {
+            Default::default()
+        };
+        // Older files have no sources nor drains.
+        let (sources, drains) = if version >= 3 {
+            (
+                read_counters(reader, &board, Tile::Source)?,
+                read_counters(reader, &board, Tile::Drain)?,
+            )
+        } else {
+            Default::default()
+        };
+
+        Ok(Self {
+            board,
+            blobs,
+            oil_blobs,
+            seed,
+            ticks,
+            sources,
+            drains,
+        })
+    }
+}
+
+fn write_blobs(writer: &mut impl Write, blobs: &Blobs) -> Result<(), Error> {
+    write_u32(writer, blobs.len() as u32)?;
+    for (index, blob) in blobs {
+        writer.write_all(&(*index as u64).to_le_bytes())?;
+        write_u32(writer, blob.points().len() as u32)?;
+        for pt in blob.points() {
+            write_u32(writer, pt.x() as u32)?;
+            write_u32(writer, pt.y() as u32)?;
+        }
+    }
+    Ok(())
+}
+
+// Blobs must cover every tile of the liquid on the board, and nothing else.
+fn read_blobs(reader: &mut impl Read, board: &Board, liquid: Tile) -> Result<Blobs, Error> {
+    let mut blobs: Blobs = Default::default();
+    for _ in 0..read_u32(reader)? {
+        let index = read_u64(reader)? as usize;
+        let mut blob: Blob = Default::default();
+        for _ in 0..read_u32(reader)? {
+            let x = read_u32(reader)? as usize;
+            let y = read_u32(reader)? as usize;
+            if board.tiles().at(x, y) != Some(&liquid) {
+                return Err(Error::Corrupted(format!(
+                    "{liquid:?} blob {index} contains ({x}, {y}) which is not {liquid:?}"
+                )));
+            }
+            blob.points_mut().insert(Point::new(x, y));
+        }
+        if blobs.insert(index, blob).is_some() {
+            return Err(Error::Corrupted(format!(
+                "duplicated {liquid:?} blob {index}"
+            )));
+        }
+    }
+
+    let in_blobs: usize = blobs.values().map(|blob| blob.points().len()).sum();
+    let on_board = (0..board.height())
+        .flat_map(|y| (0..board.width()).map(move |x| (x, y)))
+        .filter(|(x, y)| board.tiles().at(*x, *y) == Some(&liquid))
+        .count();
+    if in_blobs != on_board {
+        return Err(Error::Corrupted(format!(
+            "{liquid:?} blobs hold {in_blobs} tiles, but there are {on_board} on the board"
+        )));
+    }
+    Ok(blobs)
+}
+
+fn write_counters(writer: &mut impl Write, counters: &BTreeMap<Point, usize>) -> Result<(), Error> {
+    write_u32(writer, counters.len() as u32)?;
+    for (pt, count) in counters {
+        write_u32(writer, pt.x() as u32)?;
+        write_u32(writer, pt.y() as u32)?;
+        writer.write_all(&(*count as u64).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Every source or drain on the board must have its counter, and nothing else.
+fn read_counters(
+    reader: &mut impl Read,
+    board: &Board,
+    tile: Tile,
+) -> Result<BTreeMap<Point, usize>, Error> {
+    let mut counters = BTreeMap::new();
+    for _ in 0..read_u32(reader)? {
+        let x = read_u32(reader)? as usize;
+        let y = read_u32(reader)? as usize;
+        let count = read_u64(reader)? as usize;
+        if board.tiles().at(x, y) != Some(&tile) {
+            return Err(Error::Corrupted(format!(
+                "{tile:?} counter at ({x}, {y}) which is not {tile:?}"
+            )));
+        }
+        if counters.insert(Point::new(x, y), count).is_some() {
+            return Err(Error::Corrupted(format!(
+                "duplicated {tile:?} counter at ({x}, {y})"
+            )));
+        }
+    }
+
+    let on_board = board.tiles().positions(tile).count();
+    if counters.len() != on_board {
+        return Err(Error::Corrupted(format!(
+            "{} {tile:?} counters, but there are {on_board} on the board",
+            counters.len()
+        )));
+    }
+    Ok(counters)
+}
+
+fn tile_code(tile: Tile) -> u8 {
+    match tile {
+        Tile::Air => 0,
+        Tile::Rock => 1,
+        Tile::Water => 2,
+        Tile::Sand => 3,
+        Tile::Oil => 4,
+        Tile::Source => 5,
+        Tile::Drain => 6,
+    }
+}
+
+fn tile_from_code(code: u8) -> Option<Tile> {
+    match code {
+        0 => Some(Tile::Air),
+        1 => Some(Tile::Rock),
+        2 => Some(Tile::Water),
+        3 => Some(Tile::Sand),
+        4 => Some(Tile::Oil),
+        5 => Some(Tile::Source),
+        6 => Some(Tile::Drain),
+        _ => None,
+    }
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        blob_detector::BlobDetector,
+        board::Board,
+        engine::{Engine, EngineConfig},
+        tiles::Tile,
+    };
+
+    use super::{Error, Session};
+
+    const TILES: &str = "##########\
+                         #..oo....#\
+                         #..oo..o.#\
+                         #.~~..oo.#\
+                         #..##....#\
+                         #........#\
+                         ##########";
+
+    fn session() -> Session {
+        let board = Board::new_from_str(10, 7, TILES);
+        Session {
+            blobs: BlobDetector::new(&board, Tile::Water).detect_quick(),
+            oil_blobs: BlobDetector::new(&board, Tile::Oil).detect_quick(),
+            board,
+            seed: 2137,
+            ticks: 0,
+            sources: Default::default(),
+            drains: Default::default(),
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_session() {
+        let session = session();
+        let mut bytes = Vec::new();
+        session.save(&mut bytes).unwrap();
+
+        let loaded = Session::load(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(bytes[4..8], 3u32.to_le_bytes());
+        assert_eq!(loaded, session);
+    }
+
+    #[test]
+    fn loads_version_2() {
+        let session = session();
+        let mut bytes = Vec::new();
+        session.save(&mut bytes).unwrap();
+
+        // Same layout, only without the tick count, sources and drains.
+        bytes[4] = 2;
+        bytes.drain(24..32);
+        bytes.truncate(bytes.len() - 8);
+
+        assert_eq!(Session::load(&mut bytes.as_slice()).unwrap(), session);
+    }
+
+    #[test]
+    fn rejects_unknown_files() {
+        let mut bytes = Vec::new();
+        session().save(&mut bytes).unwrap();
+
+        bytes[0] = b'X';
+        assert!(matches!(
+            Session::load(&mut bytes.as_slice()),
+            Err(Error::BadMagic)
+        ));
+
+        bytes[0] = b'W';
+        bytes[4] = 99;
+        assert!(matches!(
+            Session::load(&mut bytes.as_slice()),
+            Err(Error::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn loads_version_1_without_oil() {
+        let mut session = session();
+        session.board = Board::new_from_str(10, 7, &TILES.replace('~', "."));
+        session.oil_blobs.clear();
+        let mut bytes = Vec::new();
+        session.save(&mut bytes).unwrap();
+
+        // Version 1 is the same as version 2, only without the trailing oil blob count.
+        bytes[4] = 1;
+        bytes.drain(24..32);
+        bytes.truncate(bytes.len() - 12);
+
+        assert_eq!(Session::load(&mut bytes.as_slice()).unwrap(), session);
+    }
+
+    #[test]
+    fn resumes_simulation_exactly() {
+        let Session {
+            mut board, seed, ..
+        } = session();
+        board.tiles_mut().set_at(5, 1, Tile::Source);
+        board.tiles_mut().set_at(8, 5, Tile::Drain);
+        let cfg = EngineConfig {
+            source_rate: 3,
+            ..Default::default()
+        };
+        let mut engine = Engine::from_board(
+            board,
+            EngineConfig {
+                seed: Some(seed),
+                ..cfg.clone()
+            },
+        );
+        for _ in 0..5 {
+            engine.tick();
+        }
+
+        let mut bytes = Vec::new();
+        engine.snapshot().save(&mut bytes).unwrap();
+        let session = Session::load(&mut bytes.as_slice()).unwrap();
+        assert_eq!(session.ticks, 5);
+        let mut resumed = Engine::resume(session, cfg);
+
+        for _ in 0..20 {
+            engine.tick();
+            resumed.tick();
+        }
+        assert!(engine.sources().values().sum::<usize>() > 0);
+        assert_eq!(engine.snapshot(), resumed.snapshot());
+    }
+
+    #[test]
+    fn resumes_simulation_with_sleeping_chunks() {
+        // Settled lake, which falls asleep, and a column of water still falling into it.
+        let mut board = Board::new(200, 150);
+        for y in 140..149 {
+            for x in 1..199 {
+                board.tiles_mut().set_at(x, y, Tile::Water);
+            }
+        }
+        for y in 5..15 {
+            for x in 20..26 {
+                board.tiles_mut().set_at(x, y, Tile::Water);
+            }
+        }
+        let cfg = EngineConfig {
+            seed: Some(3),
+            ..Default::default()
+        };
+        let mut engine = Engine::from_board(board, cfg);
+        for _ in 0..3 {
+            engine.tick();
+        }
+        let (awake, chunks) = engine.awake_chunks();
+        assert!(0 < awake && awake < chunks);
+
+        let mut resumed = Engine::resume(engine.snapshot(), Default::default());
+        for _ in 0..30 {
+            engine.tick();
+            resumed.tick();
+        }
+        assert_eq!(engine.snapshot(), resumed.snapshot());
+    }
+}
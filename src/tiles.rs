@@ -1,13 +1,35 @@
 // TODO: Clean-up unwraps
 
+use std::ops::Range;
+
+use crate::chunks::CHUNK_SIZE;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) enum Tile {
     Rock,
     Water,
     Air,
+    Sand,
+    Oil,
+    Source,
+    Drain,
 }
 
 impl Tile {
+    // In the order of declaration, so `tile as usize` is the index of the tile.
+    pub(crate) const ALL: [Tile; 7] = [
+        Tile::Rock,
+        Tile::Water,
+        Tile::Air,
+        Tile::Sand,
+        Tile::Oil,
+        Tile::Source,
+        Tile::Drain,
+    ];
+
+    // Heaviest first, lighter liquids float on top of heavier ones.
+    pub(crate) const LIQUIDS: [Tile; 2] = [Tile::Water, Tile::Oil];
+
     pub(crate) fn is_air(&self) -> bool {
         self == &Tile::Air
     }
@@ -19,8 +41,41 @@ impl Tile {
     pub(crate) fn is_water(&self) -> bool {
         self == &Tile::Water
     }
+
+    pub(crate) fn is_oil(&self) -> bool {
+        self == &Tile::Oil
+    }
+
+    pub(crate) fn is_liquid(&self) -> bool {
+        self.is_water() || self.is_oil()
+    }
+
+    pub(crate) fn is_sand(&self) -> bool {
+        self == &Tile::Sand
+    }
+
+    pub(crate) fn is_source(&self) -> bool {
+        self == &Tile::Source
+    }
+
+    pub(crate) fn is_drain(&self) -> bool {
+        self == &Tile::Drain
+    }
+
+    pub(crate) fn symbol(&self) -> char {
+        match self {
+            Tile::Rock => '#',
+            Tile::Water => 'o',
+            Tile::Air => '.',
+            Tile::Sand => 's',
+            Tile::Oil => '~',
+            Tile::Source => '+',
+            Tile::Drain => '-',
+        }
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum TileUpdateOperation {
     Paint(Tile),
     Erase,
@@ -42,60 +97,287 @@ impl TileUpdateRule {
     pub(crate) fn is_allowed(current: Option<&Tile>, op: &TileUpdateOperation) -> bool {
         match op {
             TileUpdateOperation::Paint(what) => {
-                current.map_or(false, |tile| tile.is_air())
-                    || (what.is_rock() && current.map_or(false, |tile| tile.is_water()))
+                current.is_some_and(|tile| tile.is_air())
+                    || (what.is_rock() && current.is_some_and(|tile| tile.is_liquid()))
             }
-            TileUpdateOperation::Erase => current.map_or(false, |tile| tile.is_rock()),
+            TileUpdateOperation::Erase => current.is_some_and(|tile| {
+                tile.is_rock() || tile.is_sand() || tile.is_source() || tile.is_drain()
+            }),
             TileUpdateOperation::Purge => true,
         }
     }
 }
 
-// TODO: Better use single Vec in order to enable faster swapping of items
-#[derive(Clone, Debug)]
+// Tile codes index this table, so `at()` can hand out references to decoded tiles.
+static TILES: [Tile; 7] = Tile::ALL;
+
+// Bits of the tile code, each of them is kept in its own plane.
+const PLANES: usize = 3;
+
+const _: () = assert!(Tile::ALL.len() <= 1 << PLANES);
+
+// Planes of a row of air, missing chunks are all air.
+const AIR: [u64; PLANES] = {
+    let mut planes = [0; PLANES];
+    let mut plane = 0;
+    while plane < PLANES {
+        if (Tile::Air as usize) >> plane & 1 == 1 {
+            planes[plane] = !0;
+        }
+        plane += 1;
+    }
+    planes
+};
+
+const _: () = assert!(CHUNK_SIZE == u64::BITS as usize);
+
+// Each row of the chunk is a single word in each plane.
+#[derive(Clone, Debug, PartialEq)]
+struct Chunk {
+    rows: [[u64; PLANES]; CHUNK_SIZE],
+    // Tiles other than air, the chunk is dropped when there are none left.
+    filled: usize,
+}
+
+// Each tile is stored as a 3-bit code, one bit per plane, so all tiles of a kind in a row are
+// found with a few word operations. The board is split into chunks which are only allocated
+// when there is something other than air in them.
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct Tiles {
     width: usize,
     height: usize,
-    tiles: Vec<Tile>,
+    chunks_x: usize,
+    chunks: Vec<Option<Box<Chunk>>>,
 }
 
 impl Tiles {
     pub(crate) fn _from_str(s: &str, width: usize, height: usize) -> Self {
-        Self {
-            width,
-            height,
-            tiles: s
-                .chars()
-                .map(|c| match c {
-                    '#' => Tile::Rock,
-                    '.' => Tile::Air,
-                    'o' => Tile::Water,
-                    _ => panic!("unknown tile: {c}"),
-                })
-                .collect(),
+        let mut tiles = Self::empty(width, height);
+        for (i, c) in s.chars().enumerate() {
+            let tile = match c {
+                '#' => Tile::Rock,
+                '.' => Tile::Air,
+                'o' => Tile::Water,
+                's' => Tile::Sand,
+                '~' => Tile::Oil,
+                '+' => Tile::Source,
+                '-' => Tile::Drain,
+                _ => panic!("unknown tile: {c}"),
+            };
+            tiles.set_at(i % width, i / width, tile);
         }
+        tiles
     }
 
     pub(crate) fn empty(width: usize, height: usize) -> Self {
+        let chunks_x = width.div_ceil(CHUNK_SIZE);
         Self {
             width,
             height,
-            tiles: vec![Tile::Air; height * width],
+            chunks_x,
+            chunks: vec![None; chunks_x * height.div_ceil(CHUNK_SIZE)],
         }
     }
 
     pub(crate) fn at(&self, x: usize, y: usize) -> Option<&Tile> {
-        self.within_limits(x, y)
-            .then(|| &self.tiles[y * self.width + x])
+        self.within_limits(x, y).then(|| &TILES[self.code_at(x, y)])
+    }
+
+    // Meant for the inner loops, the position is only checked in debug builds. Outside of
+    // the board it may decode the padding of the last word as a tile.
+    pub(crate) fn at_unchecked(&self, x: usize, y: usize) -> Tile {
+        debug_assert!(x < self.width && y < self.height);
+        TILES[self.code_at(x, y)]
     }
 
     pub(crate) fn set_at(&mut self, x: usize, y: usize, tile: Tile) {
-        if self.within_limits(x, y) {
-            *self.tiles.get_mut(y * self.width + x).unwrap() = tile;
+        if !self.within_limits(x, y) {
+            return;
+        }
+        let (old, new) = (self.code_at(x, y), tile as usize);
+        if old == new {
+            return;
+        }
+        let index = self.chunk_index(x, y);
+        let chunk = self.chunks[index].get_or_insert_with(|| {
+            Box::new(Chunk {
+                rows: [AIR; CHUNK_SIZE],
+                filled: 0,
+            })
+        });
+        let bit = x % CHUNK_SIZE;
+        for (plane, word) in chunk.rows[y % CHUNK_SIZE].iter_mut().enumerate() {
+            if new >> plane & 1 == 1 {
+                *word |= 1 << bit;
+            } else {
+                *word &= !(1 << bit);
+            }
         }
+        if old == Tile::Air as usize {
+            chunk.filled += 1;
+        } else if tile.is_air() {
+            chunk.filled -= 1;
+        }
+        if chunk.filled == 0 {
+            self.chunks[index] = None;
+        }
+    }
+
+    // Tiles of the row, from left to right.
+    pub(crate) fn row(&self, y: usize) -> impl Iterator<Item = Tile> + '_ {
+        (0..self.width).map(move |x| self.at_unchecked(x, y))
+    }
+
+    // Positions of all tiles of the kind, row by row.
+    pub(crate) fn positions(&self, tile: Tile) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.height).flat_map(move |y| {
+            self.runs(y, tile)
+                .into_iter()
+                .flat_map(move |run| run.map(move |x| (x, y)))
+        })
+    }
+
+    pub(crate) fn count(&self, tile: Tile) -> usize {
+        (0..self.height)
+            .flat_map(|y| self.row_mask(y, tile))
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    // Words of the row with bits set where the tile is, one word per chunk.
+    // The lowest bit is the leftmost tile.
+    pub(crate) fn row_mask(&self, y: usize, tile: Tile) -> impl Iterator<Item = u64> + '_ {
+        let code = tile as usize;
+        (0..self.chunks_x).map(move |cx| {
+            let planes = self.chunks[(y / CHUNK_SIZE) * self.chunks_x + cx]
+                .as_ref()
+                .map_or(AIR, |chunk| chunk.rows[y % CHUNK_SIZE]);
+            let mask = planes.iter().enumerate().fold(!0, |mask, (plane, word)| {
+                mask & if code >> plane & 1 == 1 {
+                    *word
+                } else {
+                    !*word
+                }
+            });
+            // Padding after the last tile of the row is not a tile.
+            let width = self.width - cx * CHUNK_SIZE;
+            if width < CHUNK_SIZE {
+                mask & ((1 << width) - 1)
+            } else {
+                mask
+            }
+        })
+    }
+
+    // Horizontal runs of the tile in the row, from left to right.
+    pub(crate) fn runs(&self, y: usize, tile: Tile) -> Vec<Range<usize>> {
+        let mut runs = Vec::new();
+        let mut start = None;
+        for (i, word) in self.row_mask(y, tile).enumerate() {
+            let base = i * CHUNK_SIZE;
+            let mut offset = 0;
+            while offset < CHUNK_SIZE {
+                let rest = word >> offset;
+                match start {
+                    None if rest == 0 => break,
+                    None => {
+                        offset += rest.trailing_zeros() as usize;
+                        start = Some(base + offset);
+                    }
+                    Some(first) => {
+                        offset += rest.trailing_ones() as usize;
+                        if offset < CHUNK_SIZE {
+                            runs.push(first..base + offset);
+                            start = None;
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(first) = start {
+            runs.push(first..self.width);
+        }
+        runs
+    }
+
+    fn code_at(&self, x: usize, y: usize) -> usize {
+        let Some(chunk) = &self.chunks[self.chunk_index(x, y)] else {
+            return Tile::Air as usize;
+        };
+        let bit = x % CHUNK_SIZE;
+        chunk.rows[y % CHUNK_SIZE]
+            .iter()
+            .enumerate()
+            .fold(0, |code, (plane, word)| {
+                code | ((word >> bit & 1) as usize) << plane
+            })
+    }
+
+    fn chunk_index(&self, x: usize, y: usize) -> usize {
+        (y / CHUNK_SIZE) * self.chunks_x + x / CHUNK_SIZE
     }
 
     fn within_limits(&self, x: usize, y: usize) -> bool {
         x < self.width && y < self.height
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Tile, TileUpdateOperation, TileUpdateRule, Tiles};
+
+    #[test]
+    fn erases_all_but_air_and_liquids() {
+        let erasable: Vec<_> = Tile::ALL
+            .into_iter()
+            .filter(|tile| TileUpdateRule::is_allowed(Some(tile), &TileUpdateOperation::Erase))
+            .collect();
+        assert_eq!(
+            erasable,
+            [Tile::Rock, Tile::Sand, Tile::Source, Tile::Drain]
+        );
+    }
+
+    #[test]
+    fn stores_every_tile() {
+        let mut tiles = Tiles::empty(70, 3);
+        for (i, tile) in Tile::ALL.into_iter().cycle().take(70 * 3).enumerate() {
+            tiles.set_at(i % 70, i / 70, tile);
+        }
+        for (i, tile) in Tile::ALL.into_iter().cycle().take(70 * 3).enumerate() {
+            assert_eq!(tiles.at(i % 70, i / 70), Some(&tile));
+        }
+        assert_eq!(tiles.at(70, 0), None);
+        assert_eq!(tiles.at(0, 3), None);
+    }
+
+    #[test]
+    fn drops_chunks_of_air() {
+        let mut tiles = Tiles::empty(200, 200);
+        tiles.set_at(150, 100, Tile::Water);
+        assert_ne!(tiles, Tiles::empty(200, 200));
+        tiles.set_at(150, 100, Tile::Air);
+        assert_eq!(tiles, Tiles::empty(200, 200));
+    }
+
+    #[test]
+    fn finds_runs_across_words() {
+        let mut tiles = Tiles::empty(130, 1);
+        for x in (2..5).chain(60..70).chain(127..130) {
+            tiles.set_at(x, 0, Tile::Water);
+        }
+        tiles.set_at(0, 0, Tile::Oil);
+        tiles.set_at(64, 0, Tile::Oil);
+
+        assert_eq!(tiles.runs(0, Tile::Water), [2..5, 60..64, 65..70, 127..130]);
+        assert_eq!(tiles.runs(0, Tile::Oil), [0..1, 64..65]);
+        assert_eq!(tiles.runs(0, Tile::Rock), []);
+        assert_eq!(
+            tiles
+                .row_mask(0, Tile::Air)
+                .map(u64::count_ones)
+                .sum::<u32>(),
+            130 - 17
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{
+    board::Board,
+    chunks::ChunkGrid,
+    tiles::{Tile, TileUpdateOperation},
+};
+
+// What the mouse does to the board.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Tool {
+    // Freehand, with the brush under the cursor.
+    #[default]
+    Brush,
+    Line,
+    Rectangle {
+        filled: bool,
+    },
+    // Region of air around the clicked tile.
+    Fill,
+}
+
+// Line or rectangle being dragged out with the mouse. Tiles only change once the button is
+// released, until then it's just previewed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Drag {
+    pub(crate) tool: Tool,
+    pub(crate) from: (usize, usize),
+    pub(crate) to: (usize, usize),
+    pub(crate) op: TileUpdateOperation,
+}
+
+impl Drag {
+    pub(crate) fn tiles(&self) -> Vec<(usize, usize)> {
+        match self.tool {
+            Tool::Line => line(self.from, self.to),
+            Tool::Rectangle { filled } => rectangle(self.from, self.to, filled),
+            Tool::Brush | Tool::Fill => Vec::new(),
+        }
+    }
+}
+
+// Bresenham's, so the line has no gaps and no corners where it steps aside.
+pub(crate) fn line(from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+    let (mut x, mut y) = (from.0 as isize, from.1 as isize);
+    let (to_x, to_y) = (to.0 as isize, to.1 as isize);
+    let (dx, dy) = ((to_x - x).abs(), -(to_y - y).abs());
+    let (step_x, step_y) = ((to_x - x).signum(), (to_y - y).signum());
+    let mut error = dx + dy;
+    let mut tiles = vec![(x as usize, y as usize)];
+    while (x, y) != (to_x, to_y) {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+        tiles.push((x as usize, y as usize));
+    }
+    tiles
+}
+
+// Corners can be given in any order.
+pub(crate) fn rectangle(
+    from: (usize, usize),
+    to: (usize, usize),
+    filled: bool,
+) -> Vec<(usize, usize)> {
+    let (left, right) = (from.0.min(to.0), from.0.max(to.0));
+    let (top, bottom) = (from.1.min(to.1), from.1.max(to.1));
+    let mut tiles = Vec::new();
+    for y in top..=bottom {
+        for x in left..=right {
+            if filled || x == left || x == right || y == top || y == bottom {
+                tiles.push((x, y));
+            }
+        }
+    }
+    tiles
+}
+
+// Air connected to the given tile through the four sides. Nothing when the tile isn't air.
+pub(crate) fn fill(board: &Board, x: usize, y: usize) -> Vec<(usize, usize)> {
+    if board.tiles().at(x, y) != Some(&Tile::Air) {
+        return Vec::new();
+    }
+    let mut visited: ChunkGrid<bool> = ChunkGrid::new(board.width(), board.height());
+    let mut pending = vec![(x, y)];
+    let mut tiles = Vec::new();
+    visited.set(x, y, true);
+    while let Some((x, y)) = pending.pop() {
+        tiles.push((x, y));
+        let neighbors = [
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        for (x, y) in neighbors {
+            if board.tiles().at(x, y) == Some(&Tile::Air) && !visited.get(x, y) {
+                visited.set(x, y, true);
+                pending.push((x, y));
+            }
+        }
+    }
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::board::Board;
+
+    use super::{fill, line, rectangle};
+
+    #[test]
+    fn draws_lines_without_gaps() {
+        assert_eq!(
+            line((1, 1), (5, 3)),
+            [(1, 1), (2, 2), (3, 2), (4, 3), (5, 3)]
+        );
+        assert_eq!(line((2, 4), (2, 1)), [(2, 4), (2, 3), (2, 2), (2, 1)]);
+        assert_eq!(line((3, 3), (3, 3)), [(3, 3)]);
+    }
+
+    #[test]
+    fn draws_rectangles() {
+        assert_eq!(rectangle((4, 3), (1, 1), true).len(), 12);
+        assert_eq!(
+            rectangle((1, 1), (3, 3), false),
+            [
+                (1, 1),
+                (2, 1),
+                (3, 1),
+                (1, 2),
+                (3, 2),
+                (1, 3),
+                (2, 3),
+                (3, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn fills_connected_air() {
+        const TILES: &str = "#######\
+                             #..#.o#\
+                             #..#..#\
+                             ####..#\
+                             #######";
+        let board = Board::new_from_str(7, 5, TILES);
+        let mut tiles = fill(&board, 1, 2);
+        tiles.sort();
+        assert_eq!(tiles, [(1, 1), (1, 2), (2, 1), (2, 2)]);
+        assert_eq!(fill(&board, 5, 3).len(), 5);
+        // Only air is filled, never the walls or the liquids.
+        assert!(fill(&board, 0, 0).is_empty());
+        assert!(fill(&board, 5, 1).is_empty());
+        assert!(fill(&board, 7, 0).is_empty());
+    }
+}